@@ -0,0 +1,122 @@
+use std::fmt;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::config::{get_optional_env_var, ConfigSource};
+
+const DEFAULT_THRESHOLD_LUX: f64 = 50.0;
+/// How long to wait for `AMBIENT_LIGHT_URL`, so a hung sensor can't hold up
+/// the scheduled turn-on.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the ambient light level comes from.
+#[derive(Clone)]
+enum AmbientLightSource {
+    /// A URL that answers with a lux reading.
+    Url {
+        url: String,
+        client: reqwest::Client,
+    },
+    /// `AMBIENT_LIGHT_LUX`, read each time so a config reload updates it.
+    Env,
+}
+
+/// Skips the scheduled turn-on while it's still bright, going by a light
+/// sensor's reading.
+#[derive(Clone)]
+pub struct DarknessCheck {
+    source: AmbientLightSource,
+    /// Readings at or above this many lux count as bright.
+    threshold: f64,
+}
+
+impl DarknessCheck {
+    /// Reads the check from `config`. It's only enabled with
+    /// `AMBIENT_LIGHT_CHECK`, and then needs `AMBIENT_LIGHT_URL` or
+    /// `AMBIENT_LIGHT_LUX` to read from.
+    pub fn from_config(config: ConfigSource) -> Option<Self> {
+        if !config.bool("AMBIENT_LIGHT_CHECK", false) {
+            return None;
+        }
+        let source = match config.optional("AMBIENT_LIGHT_URL") {
+            Some(url) => AmbientLightSource::Url {
+                url,
+                client: reqwest::Client::builder()
+                    .timeout(FETCH_TIMEOUT)
+                    .build()
+                    .expect("Failed to build the ambient light client"),
+            },
+            None if config.optional("AMBIENT_LIGHT_LUX").is_some() => AmbientLightSource::Env,
+            None => panic!("AMBIENT_LIGHT_CHECK needs AMBIENT_LIGHT_URL or AMBIENT_LIGHT_LUX"),
+        };
+        Some(Self {
+            source,
+            threshold: config.parsed("AMBIENT_LIGHT_THRESHOLD", DEFAULT_THRESHOLD_LUX),
+        })
+    }
+
+    /// Reads the current ambient light level in lux.
+    async fn lux(&self) -> Result<f64, String> {
+        match &self.source {
+            AmbientLightSource::Url { url, client } => {
+                let body = client
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| format!("Failed to fetch ambient light: {}", e))?
+                    .text()
+                    .await
+                    .map_err(|e| format!("Failed to read ambient light: {}", e))?;
+                parse_reading(&body)
+            }
+            AmbientLightSource::Env => parse_reading(
+                &get_optional_env_var("AMBIENT_LIGHT_LUX")
+                    .ok_or_else(|| "AMBIENT_LIGHT_LUX isn't set".to_string())?,
+            ),
+        }
+    }
+
+    /// Whether it's bright enough to leave the light off. A failed or timed
+    /// out reading counts as dark, so the light still comes on.
+    pub async fn is_bright(&self) -> bool {
+        match self.lux().await {
+            Ok(lux) => {
+                info!(
+                    "Ambient light is {} lux, threshold {} lux",
+                    lux, self.threshold
+                );
+                lux >= self.threshold
+            }
+            Err(e) => {
+                warn!("Turning on regardless of ambient light: {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl fmt::Display for DarknessCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self.source {
+            AmbientLightSource::Url { .. } => "AMBIENT_LIGHT_URL",
+            AmbientLightSource::Env => "AMBIENT_LIGHT_LUX",
+        };
+        write!(f, "below {} lux from {}", self.threshold, source)
+    }
+}
+
+/// Parses a reading such as `12.5` or `{"lux": 12.5}`.
+fn parse_reading(body: &str) -> Result<f64, String> {
+    let body = body.trim();
+    body.parse()
+        .ok()
+        .or_else(|| {
+            serde_json::from_str::<serde_json::Value>(body)
+                .ok()?
+                .get("lux")?
+                .as_f64()
+        })
+        .ok_or_else(|| format!("Expected a lux reading, got {}", body))
+}
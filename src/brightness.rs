@@ -0,0 +1,116 @@
+use chrono::NaiveTime;
+use std::fmt;
+use std::str::FromStr;
+
+/// Brightness to turn the light on at, by hour of the day. Each entry holds
+/// from its hour until the next one, wrapping around midnight.
+#[derive(Clone, Debug)]
+pub struct BrightnessCurve(Vec<(u32, u8)>);
+
+impl FromStr for BrightnessCurve {
+    type Err = String;
+
+    /// Parses e.g. `17=100,21=60,23=30`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut points = s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (hour, percent) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected <hour>=<brightness>, got {}", entry))?;
+                let hour: u32 = hour
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|hour| *hour < 24)
+                    .ok_or_else(|| format!("Invalid hour in {}", entry))?;
+                let percent: u8 = percent
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|percent| (1..=100).contains(percent))
+                    .ok_or_else(|| format!("Invalid brightness in {}", entry))?;
+                Ok((hour, percent))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if points.is_empty() {
+            return Err("Brightness curve is empty".to_string());
+        }
+        points.sort_by_key(|&(hour, _)| hour);
+        Ok(Self(points))
+    }
+}
+
+impl fmt::Display for BrightnessCurve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let points: Vec<String> = self
+            .0
+            .iter()
+            .map(|(hour, percent)| format!("{}={}", hour, percent))
+            .collect();
+        f.write_str(&points.join(","))
+    }
+}
+
+impl BrightnessCurve {
+    /// The brightness for the given local hour.
+    pub fn at(&self, hour: u32) -> u8 {
+        self.0
+            .iter()
+            .rev()
+            .find(|&&(start, _)| start <= hour)
+            // Before the first entry, the last one is still in effect from
+            // the previous day
+            .or_else(|| self.0.last())
+            .map(|&(_, percent)| percent)
+            .unwrap_or(100)
+    }
+}
+
+/// A daily window such as `22:00-07:00`, which can wrap around midnight.
+#[derive(Clone, Copy, Debug)]
+pub struct QuietHours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|_| format!("Expected a time like 22:00, got {}", time.trim()))
+        };
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("Expected <start>-<end>, got {}", s))?;
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
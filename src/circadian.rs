@@ -0,0 +1,108 @@
+use chrono::NaiveTime;
+use std::fmt;
+use std::str::FromStr;
+
+/// Color temperatures a bulb shifts through in the evening, e.g.
+/// `19:00=4000,21:00=3000,23:00=2200`. Between two points the temperature
+/// moves linearly. Before the first point and after the last, the curve
+/// doesn't apply. Points are in the order given and may run past midnight,
+/// e.g. `20:00=4000,00:30=2200`.
+#[derive(Clone, Debug)]
+pub struct ColorTempCurve(Vec<(NaiveTime, u16)>);
+
+impl FromStr for ColorTempCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let points: Vec<(NaiveTime, u16)> = s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (time, kelvin) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected <time>=<kelvin>, got {}", entry))?;
+                let time = NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                    .map_err(|_| format!("Invalid time in {}", entry))?;
+                let kelvin: u16 = kelvin
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|kelvin| (1000..=10000).contains(kelvin))
+                    .ok_or_else(|| format!("Invalid color temperature in {}", entry))?;
+                Ok((time, kelvin))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if points.len() < 2 {
+            return Err("Color temperature curve needs at least two points".to_string());
+        }
+        let offsets: Vec<i64> = points
+            .iter()
+            .map(|&(time, _)| seconds_after(points[0].0, time))
+            .collect();
+        if offsets.windows(2).any(|pair| pair[1] <= pair[0]) {
+            return Err(
+                "Color temperature curve points must be in order, within a day of the first"
+                    .to_string(),
+            );
+        }
+        Ok(Self(points))
+    }
+}
+
+impl fmt::Display for ColorTempCurve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let points: Vec<String> = self
+            .0
+            .iter()
+            .map(|(time, kelvin)| format!("{}={}", time.format("%H:%M"), kelvin))
+            .collect();
+        f.write_str(&points.join(","))
+    }
+}
+
+impl ColorTempCurve {
+    /// The color temperature for the given local time, or `None` outside
+    /// the curve.
+    pub fn at(&self, time: NaiveTime) -> Option<u16> {
+        let first = self.0[0].0;
+        let time = seconds_after(first, time);
+        self.0.windows(2).find_map(|pair| {
+            let ((start, from), (end, to)) = (pair[0], pair[1]);
+            let (start, end) = (seconds_after(first, start), seconds_after(first, end));
+            if time < start || time > end {
+                return None;
+            }
+            let span = end - start;
+            let elapsed = time - start;
+            let kelvin =
+                f64::from(from) + (f64::from(to) - f64::from(from)) * elapsed as f64 / span as f64;
+            Some(kelvin.round() as u16)
+        })
+    }
+}
+
+/// How long after `start` the clock next reads `time`, wrapping past midnight.
+fn seconds_after(start: NaiveTime, time: NaiveTime) -> i64 {
+    (time - start).num_seconds().rem_euclid(24 * 60 * 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(value, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn curve_runs_past_midnight() {
+        let curve: ColorTempCurve = "20:00=4000,00:30=2200".parse().unwrap();
+
+        assert_eq!(curve.at(time("20:00")), Some(4000));
+        assert_eq!(curve.at(time("22:15")), Some(3100));
+        assert_eq!(curve.at(time("00:30")), Some(2200));
+        assert_eq!(curve.at(time("12:00")), None);
+        assert_eq!(curve.at(time("01:00")), None);
+    }
+}
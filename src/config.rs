@@ -0,0 +1,181 @@
+use std::str::FromStr;
+
+use crate::secrets::{get_secret, SecretProvider};
+
+/// Variables the bot can't run without.
+const REQUIRED_ENV_VARS: &[&str] = &[
+    "DISCORD_TOKEN",
+    "KASA_DEVICE_IP",
+    "KASA_USERNAME",
+    "KASA_PASSWORD",
+];
+
+pub fn get_env_var(key: &str) -> String {
+    get_secret(key).unwrap_or_else(|| panic!("Expected {key} in environment"))
+}
+
+pub fn get_optional_env_var(key: &str) -> Option<String> {
+    ConfigSource::env().optional(key)
+}
+
+pub fn get_bool_env_var(key: &str, default: bool) -> bool {
+    ConfigSource::env().bool(key, default)
+}
+
+pub fn get_parsed_env_var<T: FromStr>(key: &str, default: T) -> T {
+    ConfigSource::env().parsed(key, default)
+}
+
+/// The installed secret provider, falling back to the environment.
+struct Environment;
+
+impl SecretProvider for Environment {
+    fn get(&self, key: &str) -> Option<String> {
+        get_secret(key)
+    }
+}
+
+/// Typed settings read from a [`SecretProvider`]. The `get_*_env_var`
+/// functions read from the environment; a handler can be built from any
+/// other source, such as fixed values in tests.
+#[derive(Clone, Copy)]
+pub struct ConfigSource<'a> {
+    provider: &'a dyn SecretProvider,
+}
+
+impl<'a> ConfigSource<'a> {
+    #[cfg(test)]
+    pub fn new(provider: &'a dyn SecretProvider) -> Self {
+        Self { provider }
+    }
+
+    /// Settings from the installed provider and the environment.
+    pub fn env() -> ConfigSource<'static> {
+        ConfigSource {
+            provider: &Environment,
+        }
+    }
+
+    /// The value of `key`, treating an empty value as unset.
+    pub fn optional(&self, key: &str) -> Option<String> {
+        self.provider.get(key).filter(|val| !val.is_empty())
+    }
+
+    pub fn bool(&self, key: &str, default: bool) -> bool {
+        match self.optional(key) {
+            Some(val) => match val.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => true,
+                "0" | "false" | "no" | "off" => false,
+                _ => panic!("{key} must be true or false, got {val}"),
+            },
+            None => default,
+        }
+    }
+
+    pub fn parsed<T: FromStr>(&self, key: &str, default: T) -> T {
+        match self.optional(key) {
+            Some(val) => val
+                .parse()
+                .unwrap_or_else(|_| panic!("{key} has an invalid value: {val}")),
+            None => default,
+        }
+    }
+
+    /// Like [`Self::parsed`], but also rejects zero.
+    pub fn positive(&self, key: &str, default: u32) -> u32 {
+        let val = self.parsed(key, default);
+        if val == 0 {
+            panic!("{key} must be a positive number, got {val}");
+        }
+        val
+    }
+}
+
+/// Checks that every required variable is set, returning all of the missing
+/// ones at once so they can be fixed in a single pass.
+pub fn validate_config() -> Result<(), String> {
+    let missing: Vec<&str> = REQUIRED_ENV_VARS
+        .iter()
+        .copied()
+        .filter(|key| get_optional_env_var(key).is_none())
+        .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Missing required environment variables: {}",
+            missing.join(", ")
+        ))
+    }
+}
+
+/// Where `/setup` saves settings. It's the same file dotenv loads at startup.
+pub const ENV_FILE: &str = ".env";
+
+/// Re-reads the env file into the process environment, overriding values
+/// loaded at startup.
+pub fn reload_env_file() -> Result<(), String> {
+    // dotenv never overrides variables that are already set, so set them
+    // ourselves. The iterator is deprecated but has no replacement in 0.15.
+    #[allow(deprecated)]
+    let entries = dotenv::from_filename_iter(ENV_FILE)
+        .map_err(|e| format!("Failed to read {}: {}", ENV_FILE, e))?;
+    for entry in entries {
+        let (key, value) = entry.map_err(|e| format!("Failed to parse {}: {}", ENV_FILE, e))?;
+        std::env::set_var(key, value);
+    }
+    Ok(())
+}
+
+/// Sets variables in the env file, replacing existing assignments and keeping
+/// everything else as is. Changes take effect on the next start.
+pub fn save_env_vars(updates: &[(&str, &str)]) -> Result<(), String> {
+    let existing = match std::fs::read_to_string(ENV_FILE) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", ENV_FILE, e)),
+    };
+
+    let mut remaining: Vec<&(&str, &str)> = updates.iter().collect();
+    let mut lines: Vec<String> = existing
+        .lines()
+        .map(|line| {
+            let key = line
+                .trim_start()
+                .trim_start_matches("export ")
+                .split('=')
+                .next()
+                .unwrap_or_default()
+                .trim();
+            match remaining.iter().position(|(update, _)| *update == key) {
+                Some(index) => {
+                    let (key, value) = remaining.remove(index);
+                    format!("{}={}", key, quote_env_value(value))
+                }
+                None => line.to_string(),
+            }
+        })
+        .collect();
+    lines.extend(
+        remaining
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, quote_env_value(value))),
+    );
+
+    std::fs::write(ENV_FILE, lines.join("\n") + "\n")
+        .map_err(|e| format!("Failed to write {}: {}", ENV_FILE, e))
+}
+
+/// Quotes a value so dotenv reads it back verbatim.
+fn quote_env_value(value: &str) -> String {
+    if value.contains('\'') {
+        let escaped = value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "\\$");
+        format!("\"{}\"", escaped)
+    } else {
+        format!("'{}'", value)
+    }
+}
@@ -0,0 +1,276 @@
+use serenity::async_trait;
+use std::path::Path;
+use tokio::process::Command;
+use tracing::{error, info};
+
+use crate::config::get_optional_env_var;
+use crate::device::Device;
+
+/// Sends commands to a light. Abstracted so the handler can be exercised
+/// without real hardware.
+#[async_trait]
+pub trait LightController: Send + Sync {
+    /// Runs a kasa command such as `["on"]` or `["feature", "state"]` against
+    /// `device`, returning its stdout.
+    async fn run(&self, device: &Device, args: &[&str]) -> Result<String, String>;
+}
+
+/// Runs commands through the python-kasa CLI.
+pub struct KasaCliController {
+    command: String,
+    command_args: Vec<String>,
+    dir: String,
+}
+
+/// Where python-kasa projects are commonly installed, checked in order when
+/// `KASA_DIR` isn't set.
+const KASA_DIR_CANDIDATES: &[&str] = &["./kasa", "~/.kasa", "/opt/python-kasa"];
+
+impl KasaCliController {
+    pub fn from_env() -> Self {
+        // Without a project directory to `uv run` in, fall back to a kasa
+        // installed on the PATH
+        let (dir, default_command, default_args) = match get_optional_env_var("KASA_DIR") {
+            Some(dir) => (dir, "uv", "run,kasa"),
+            None => match discover_kasa_dir() {
+                Some(dir) => {
+                    info!("KASA_DIR isn't set, using {}", dir);
+                    (dir, "uv", "run,kasa")
+                }
+                None if find_on_path("kasa") => {
+                    info!("KASA_DIR isn't set, using kasa from the PATH");
+                    (".".to_string(), "kasa", "")
+                }
+                None => panic!(
+                    "KASA_DIR isn't set and python-kasa wasn't found in {} or on the PATH",
+                    KASA_DIR_CANDIDATES.join(", ")
+                ),
+            },
+        };
+
+        let (command, command_args) = command_with_args(
+            get_optional_env_var("KASA_COMMAND"),
+            get_optional_env_var("KASA_COMMAND_ARGS"),
+            default_command,
+            default_args,
+        );
+
+        Self {
+            command,
+            command_args,
+            dir,
+        }
+    }
+}
+
+/// Picks the command and its leading arguments. The default `run,kasa` only
+/// makes sense for the default `uv`, so overriding the command drops it.
+fn command_with_args(
+    command: Option<String>,
+    args: Option<String>,
+    default_command: &str,
+    default_args: &str,
+) -> (String, Vec<String>) {
+    let args = match (&command, args) {
+        (_, Some(args)) => args,
+        (Some(_), None) => String::new(),
+        (None, None) => default_args.to_string(),
+    };
+    let args = args
+        .split(',')
+        .map(str::trim)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from)
+        .collect();
+    (command.unwrap_or_else(|| default_command.to_string()), args)
+}
+
+/// The first of the usual install directories that exists.
+fn discover_kasa_dir() -> Option<String> {
+    let home = std::env::var("HOME").ok();
+    KASA_DIR_CANDIDATES
+        .iter()
+        .filter_map(|candidate| match candidate.strip_prefix("~/") {
+            Some(rest) => home.as_ref().map(|home| format!("{}/{}", home, rest)),
+            None => Some(candidate.to_string()),
+        })
+        .find(|dir| Path::new(dir).is_dir())
+}
+
+/// Redacts credentials from kasa arguments so they can be logged: the values
+/// after `--username` and `--password`, and anything else mentioning them,
+/// such as the JSON for a cloud bind.
+fn masked_args<'a>(args: &[&'a str]) -> Vec<&'a str> {
+    let mut mask_next = false;
+    args.iter()
+        .map(|&arg| {
+            let is_flag = matches!(arg, "--username" | "--password");
+            let masked = if is_flag {
+                arg
+            } else if mask_next || arg.contains("username") || arg.contains("password") {
+                "[MASKED]"
+            } else {
+                arg
+            };
+            mask_next = is_flag;
+            masked
+        })
+        .collect()
+}
+
+/// Whether an executable called `name` is on the PATH, like `which`.
+fn find_on_path(name: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false)
+}
+
+#[async_trait]
+impl LightController for KasaCliController {
+    async fn run(&self, device: &Device, args: &[&str]) -> Result<String, String> {
+        let credentials = device.credentials();
+        let args: Vec<&str> = [
+            "--host",
+            &device.host,
+            "--username",
+            &credentials.username,
+            "--password",
+            &credentials.password,
+        ]
+        .into_iter()
+        .chain(args.iter().copied())
+        .collect();
+        info!("Executing kasa command with args: {:?}", masked_args(&args));
+
+        let mut command = Command::new(&self.command);
+        command
+            .args(&self.command_args)
+            .current_dir(&self.dir)
+            .args(&args)
+            // Stops kasa if the command times out
+            .kill_on_drop(true);
+
+        let output = command
+            .output()
+            .await
+            .map_err(|e| format!("Failed to execute kasa command: {}", e))?;
+
+        // stderr is only logged, but stdout gets parsed, so replacement
+        // characters there would just cause a confusing error later
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8(output.stdout).map_err(|e| {
+            error!(
+                "Kasa command output wasn't valid UTF-8: {}",
+                String::from_utf8_lossy(e.as_bytes())
+            );
+            format!("Kasa command output wasn't valid UTF-8: {}", e.utf8_error())
+        })?;
+
+        info!("Kasa command stdout: {}", stdout);
+        if !stderr.is_empty() {
+            error!("Kasa command stderr: {}", stderr);
+        }
+
+        if !output.status.success() {
+            // The kasa CLI reports some errors (e.g. unknown features) on stdout
+            let detail: &str = if stderr.trim().is_empty() {
+                &stdout
+            } else {
+                &stderr
+            };
+            return Err(format!("Command failed: {}", detail));
+        }
+
+        Ok(stdout)
+    }
+}
+
+#[cfg(test)]
+impl KasaCliController {
+    /// Runs `command` in place of kasa, e.g. `true` for a dry run.
+    pub fn with_command(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            command_args: Vec::new(),
+            dir: ".".to_string(),
+        }
+    }
+}
+
+/// Records every command instead of talking to a device.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockController {
+    calls: std::sync::Mutex<Vec<Vec<String>>>,
+}
+
+#[cfg(test)]
+impl MockController {
+    /// The arguments of every command run so far, in order.
+    pub fn calls(&self) -> Vec<Vec<String>> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl LightController for MockController {
+    async fn run(&self, _device: &Device, args: &[&str]) -> Result<String, String> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(args.iter().map(|arg| arg.to_string()).collect());
+        Ok(String::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overriding_the_command_drops_the_uv_args() {
+        let (command, args) = command_with_args(Some("kasa".to_string()), None, "uv", "run,kasa");
+
+        assert_eq!(command, "kasa");
+        assert!(args.is_empty(), "{:?}", args);
+    }
+
+    #[test]
+    fn masked_args_hide_credential_values() {
+        let args = [
+            "--host",
+            "192.168.1.50",
+            "--username",
+            "me@example.com",
+            "--password",
+            "hunter2",
+            "command",
+            "--module",
+            "cnCloud",
+            "bind",
+            r#"{"username":"me@example.com","password":"hunter2"}"#,
+        ];
+
+        let masked = masked_args(&args);
+
+        assert!(
+            masked
+                .iter()
+                .all(|arg| !arg.contains("hunter2") && !arg.contains("me@example.com")),
+            "{:?}",
+            masked
+        );
+        assert_eq!(
+            masked[..6],
+            [
+                "--host",
+                "192.168.1.50",
+                "--username",
+                "[MASKED]",
+                "--password",
+                "[MASKED]"
+            ]
+        );
+    }
+}
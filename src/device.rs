@@ -0,0 +1,157 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::config::{get_env_var, get_optional_env_var, get_parsed_env_var, ConfigSource};
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn from_env() -> Self {
+        Self {
+            username: get_env_var("KASA_USERNAME"),
+            password: get_env_var("KASA_PASSWORD"),
+        }
+    }
+}
+
+/// What sort of Kasa device a light is, which decides the features it has.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    Plug,
+    Dimmer,
+    Bulb,
+    Strip,
+}
+
+impl FromStr for DeviceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "plug" => Ok(Self::Plug),
+            "dimmer" => Ok(Self::Dimmer),
+            "bulb" => Ok(Self::Bulb),
+            "strip" => Ok(Self::Strip),
+            other => Err(format!("Unknown device kind: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Plug => "plug",
+            Self::Dimmer => "dimmer",
+            Self::Bulb => "bulb",
+            Self::Strip => "strip",
+        })
+    }
+}
+
+impl DeviceKind {
+    /// Bulbs have no status LED to switch.
+    pub fn has_led(self) -> bool {
+        self != Self::Bulb
+    }
+}
+
+/// A light the bot controls.
+#[derive(Clone)]
+pub struct Device {
+    pub name: String,
+    pub host: String,
+    /// Shared between copies of the device, so rotated credentials reach
+    /// all of them.
+    pub credentials: Arc<RwLock<Credentials>>,
+    pub kind: DeviceKind,
+    /// How long commands to this device may take, overriding `KASA_TIMEOUT_SECS`.
+    pub timeout: Option<Duration>,
+}
+
+/// A timeout in seconds read from `key`, if set.
+pub fn timeout_from_config(config: ConfigSource, key: &str) -> Option<Duration> {
+    config.optional(key).map(|secs| {
+        secs.parse()
+            .ok()
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| panic!("{key} must be a positive number of seconds, got {secs}"))
+    })
+}
+
+impl Device {
+    pub fn primary_from_env() -> Self {
+        Self {
+            name: get_optional_env_var("KASA_DEVICE_NAME").unwrap_or_else(|| "Light".to_string()),
+            host: get_env_var("KASA_DEVICE_IP"),
+            credentials: Arc::new(RwLock::new(Credentials::from_env())),
+            kind: get_parsed_env_var("KASA_DEVICE_KIND", DeviceKind::Plug),
+            timeout: timeout_from_config(ConfigSource::env(), "KASA_DEVICE_TIMEOUT_SECS"),
+        }
+    }
+
+    /// The light that mirrors the primary one, if configured. It shares the
+    /// primary's credentials.
+    pub fn secondary_from_env(credentials: &Arc<RwLock<Credentials>>) -> Option<Self> {
+        Some(Self {
+            name: get_optional_env_var("KASA_SECONDARY_DEVICE_NAME")
+                .unwrap_or_else(|| "Secondary light".to_string()),
+            host: get_optional_env_var("KASA_SECONDARY_DEVICE_IP")?,
+            credentials: credentials.clone(),
+            kind: get_parsed_env_var("KASA_SECONDARY_DEVICE_KIND", DeviceKind::Plug),
+            timeout: timeout_from_config(ConfigSource::env(), "KASA_SECONDARY_DEVICE_TIMEOUT_SECS"),
+        })
+    }
+
+    /// The credentials as they are now.
+    pub fn credentials(&self) -> Credentials {
+        self.credentials.read().unwrap().clone()
+    }
+}
+
+/// Lights that are switched together from the control panel.
+#[derive(Clone)]
+pub struct DeviceGroup {
+    pub name: String,
+    pub devices: Vec<Device>,
+}
+
+/// Parses `DEVICE_GROUPS`, e.g. `Whole house=Light,Secondary light;Porch=Light`,
+/// resolving the member names against `devices`.
+pub fn parse_device_groups(value: &str, devices: &[Device]) -> Result<Vec<DeviceGroup>, String> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, members) = entry.split_once('=').ok_or_else(|| {
+                format!("Device groups must look like <name>=<devices>, got {entry}")
+            })?;
+            let devices = members
+                .split(',')
+                .map(str::trim)
+                .filter(|member| !member.is_empty())
+                .map(|member| {
+                    devices
+                        .iter()
+                        .find(|device| device.name == member)
+                        .cloned()
+                        .ok_or_else(|| format!("Device group {name} names unknown device {member}"))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if devices.is_empty() {
+                return Err(format!("Device group {name} has no devices"));
+            }
+            Ok(DeviceGroup {
+                name: name.trim().to_string(),
+                devices,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use tokio::sync::RwLock;
+use tracing::{error, warn};
+
+/// Something someone did to the light.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// The button or command, e.g. `button light_off` or `/toggle`.
+    pub action: String,
+    pub user_id: u64,
+    /// Unix timestamp of the action.
+    pub at: i64,
+    /// The first line of the reply.
+    pub outcome: String,
+}
+
+/// The most recent actions, optionally saved to a JSON file so they survive
+/// restarts.
+pub struct History {
+    path: Option<PathBuf>,
+    capacity: usize,
+    entries: RwLock<VecDeque<HistoryEntry>>,
+}
+
+impl History {
+    /// Loads the history saved at `path`, if there is one, keeping at most
+    /// `capacity` entries.
+    pub fn load(path: Option<PathBuf>, capacity: usize) -> Self {
+        let mut entries = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                    warn!("Ignoring unreadable history in {:?}: {}", path, e);
+                    VecDeque::new()
+                }),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+                Err(e) => {
+                    warn!("Failed to read history from {:?}: {}", path, e);
+                    VecDeque::new()
+                }
+            },
+            None => VecDeque::new(),
+        };
+        while entries.len() > capacity {
+            entries.pop_front();
+        }
+        Self {
+            path,
+            capacity,
+            entries: RwLock::new(entries),
+        }
+    }
+
+    /// Adds an entry, dropping the oldest once full, and saves the history.
+    pub async fn record(&self, entry: HistoryEntry) {
+        {
+            let mut entries = self.entries.write().await;
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+            entries.push_back(entry);
+        }
+        let Some(path) = &self.path else {
+            return;
+        };
+        match serde_json::to_string(&*self.entries.read().await) {
+            Ok(contents) => {
+                if let Err(e) = tokio::fs::write(path, contents).await {
+                    error!("Failed to save history to {:?}: {}", path, e);
+                }
+            }
+            Err(e) => error!("Failed to serialize history: {}", e),
+        }
+    }
+
+    /// Up to `count` of the latest entries, newest first.
+    pub async fn recent(&self, count: usize) -> Vec<HistoryEntry> {
+        self.entries
+            .read()
+            .await
+            .iter()
+            .rev()
+            .take(count)
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,105 @@
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+/// Parses the output of a kasa command run with `--json`.
+pub fn parse_json<T: DeserializeOwned>(stdout: &str) -> Result<T, String> {
+    serde_json::from_str(stdout.trim())
+        .map_err(|e| format!("Unexpected kasa output ({}): {}", e, stdout.trim()))
+}
+
+/// The light part of a bulb's sysinfo.
+#[derive(Debug, Default, Deserialize)]
+pub struct LightState {
+    #[serde(default)]
+    pub on_off: u8,
+    pub brightness: Option<u8>,
+    pub hue: Option<u16>,
+    pub saturation: Option<u8>,
+    /// Zero while the bulb is showing a color rather than a white.
+    pub color_temp: Option<u16>,
+    /// What an off bulb comes back on with.
+    pub dft_on_state: Option<Box<LightState>>,
+}
+
+/// The fields of `kasa sysinfo` the bot uses. Older devices report
+/// `relay_state` and `sw_ver`, newer ones `device_on` and `fw_ver`.
+#[derive(Debug, Default, Deserialize)]
+pub struct SysInfo {
+    #[serde(default)]
+    pub model: String,
+    #[serde(default, alias = "fw_ver")]
+    pub sw_ver: String,
+    #[serde(default)]
+    pub hw_ver: String,
+    /// Which optional modules the device has, e.g. `TIM:ENE`.
+    #[serde(default)]
+    pub feature: String,
+    relay_state: Option<u8>,
+    device_on: Option<bool>,
+    light_state: Option<LightState>,
+    brightness: Option<u8>,
+    hue: Option<u16>,
+    saturation: Option<u8>,
+    color_temp: Option<u16>,
+    /// Only reported by devices with a nightlight.
+    night_mode: Option<serde_json::Value>,
+}
+
+impl SysInfo {
+    pub fn is_on(&self) -> Option<bool> {
+        self.device_on
+            .or(self.relay_state.map(|state| state == 1))
+            .or(self.light_state.as_ref().map(|light| light.on_off == 1))
+    }
+
+    /// The brightness percentage, for devices that dim.
+    pub fn brightness(&self) -> Option<u8> {
+        self.brightness
+            .or(self.light_field(|light| light.brightness))
+    }
+
+    /// The white color temperature in kelvin, unless the light is showing a color.
+    pub fn color_temp(&self) -> Option<u16> {
+        self.color_temp
+            .or(self.light_field(|light| light.color_temp))
+            .filter(|&kelvin| kelvin > 0)
+    }
+
+    /// The hue and saturation, for color bulbs.
+    pub fn hue_saturation(&self) -> Option<(u16, u8)> {
+        let hue = self.hue.or(self.light_field(|light| light.hue))?;
+        let saturation = self
+            .saturation
+            .or(self.light_field(|light| light.saturation))?;
+        Some((hue, saturation))
+    }
+
+    /// Reads a bulb's light setting, falling back to the one it comes back
+    /// on with while it's off.
+    fn light_field<T>(&self, field: impl Fn(&LightState) -> Option<T>) -> Option<T> {
+        let light = self.light_state.as_ref()?;
+        field(light).or_else(|| light.dft_on_state.as_deref().and_then(&field))
+    }
+
+    pub fn has_emeter(&self) -> bool {
+        self.feature.contains("ENE")
+    }
+
+    pub fn has_nightlight(&self) -> bool {
+        self.night_mode.is_some()
+    }
+}
+
+/// A realtime reading from `kasa emeter`. Older firmware reports watts,
+/// newer firmware milliwatts.
+#[derive(Debug, Default, Deserialize)]
+pub struct EmeterStatus {
+    power: Option<f64>,
+    power_mw: Option<f64>,
+}
+
+impl EmeterStatus {
+    pub fn watts(&self) -> Option<f64> {
+        self.power.or(self.power_mw.map(|mw| mw / 1000.0))
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+
+/// Translations that ship with the bot.
+const BUILT_IN: &str = include_str!("translations.json");
+
+/// Translations of the bot's replies, keyed by Discord locale and then by
+/// the English text. Anything without a translation stays in English.
+pub struct Translations {
+    locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl Translations {
+    /// Loads the built-in translations, then any from `path`, which take
+    /// precedence over them. The file uses the same layout, e.g.
+    /// `{"fr": {"Light turned on!": "Lumière allumée !"}}`.
+    pub fn load(path: Option<&str>) -> Result<Self, String> {
+        let mut locales: HashMap<String, HashMap<String, String>> =
+            serde_json::from_str(BUILT_IN).expect("built-in translations are valid JSON");
+        if let Some(path) = path {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read translations from {}: {}", path, e))?;
+            let overrides: HashMap<String, HashMap<String, String>> =
+                serde_json::from_str(&contents)
+                    .map_err(|e| format!("Translations in {} are invalid: {}", path, e))?;
+            for (locale, messages) in overrides {
+                locales.entry(locale).or_default().extend(messages);
+            }
+        }
+        Ok(Self { locales })
+    }
+
+    /// Translates `text` into `locale`. Regional locales such as `es-ES`
+    /// fall back to their base language.
+    pub fn translate<'a>(&'a self, locale: &str, text: &'a str) -> &'a str {
+        let base = locale.split('-').next().unwrap_or(locale);
+        [locale, base]
+            .into_iter()
+            .filter_map(|locale| self.locales.get(locale)?.get(text))
+            .next()
+            .map_or(text, String::as_str)
+    }
+}
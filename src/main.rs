@@ -1,375 +1,6559 @@
-use chrono::{DateTime, Local, Timelike, Utc};
+mod ambient;
+mod brightness;
+mod circadian;
+mod config;
+mod controller;
+mod device;
+mod history;
+mod kasa;
+mod locale;
+mod metrics;
+mod rate_limit;
+mod schedule;
+mod secrets;
+mod snapshots;
+mod stats;
+mod units;
+mod webhook;
+
+use chrono::{DateTime, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc};
 use chrono_tz::America::Toronto;
-use std::env;
-use std::process::Command;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use chrono_tz::Tz;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinHandle;
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use serenity::all::*;
+use serenity::async_trait;
+use serenity::builder::{CreateActionRow, CreateButton};
+use serenity::futures::future::join_all;
+
+use crate::ambient::DarknessCheck;
+use crate::brightness::{BrightnessCurve, QuietHours};
+use crate::circadian::ColorTempCurve;
+use crate::config::{
+    get_bool_env_var, get_env_var, get_optional_env_var, reload_env_file, save_env_vars,
+    validate_config, ConfigSource, ENV_FILE,
+};
+use crate::controller::{KasaCliController, LightController};
+use crate::device::{
+    parse_device_groups, timeout_from_config, Credentials, Device, DeviceGroup, DeviceKind,
+};
+use crate::history::{History, HistoryEntry};
+use crate::kasa::{parse_json, EmeterStatus, SysInfo};
+use crate::locale::Translations;
+use crate::metrics::OnTimeMetrics;
+use crate::rate_limit::RateLimiter;
+use crate::schedule::{
+    parse_blackout_windows, parse_scheduled_actions, Action, BlackoutWindow, JobSchedule,
+    SchedulePrecedence, ScheduledAction,
+};
+use crate::secrets::{set_secret_provider, HttpSecretProvider};
+use crate::snapshots::{Snapshot, SnapshotStore};
+use crate::stats::UsageStats;
+use crate::units::Units;
+use crate::webhook::{ActionWebhook, StateWebhook};
+
+const DEFAULT_CONTROL_CHANNEL_NAME: &str = "light-controls";
+const SETUP_MODAL_ID: &str = "setup";
+const MAX_AUTO_OFF_MINUTES: u64 = 24 * 60;
+const MAX_PAUSE_MINUTES: u64 = 7 * 24 * 60;
+/// The furthest `/shift` moves a job, either way.
+const MAX_SHIFT_MINUTES: i64 = 12 * 60;
+const DISCORD_MISSING_ACCESS: isize = 50001;
+const DISCORD_MISSING_PERMISSIONS: isize = 50013;
+const MAX_FALLBACK_CHANNEL_ATTEMPTS: usize = 5;
+/// The scheduled jobs that `/schedule disable` can turn off.
+const SCHEDULED_JOBS: [&str; 3] = ["off", "on", "led"];
+/// Cron expression for jobs that check the local time themselves.
+const EVERY_MINUTE: &str = "0 * * * * *";
+const DEFAULT_MANUAL_OVERRIDE_MINUTES: u64 = 60;
+const DEFAULT_STATS_FILE: &str = "usage_stats.json";
+const DEFAULT_SNAPSHOTS_FILE: &str = "snapshots.json";
+const STATS_LEADERBOARD_SIZE: usize = 10;
+const DEFAULT_HISTORY_SIZE: usize = 100;
+/// How many scheduled fires `/drift` remembers.
+const DRIFT_SAMPLES: usize = 50;
+/// How many of the latest fires `/drift` lists.
+const DRIFT_RECENT: usize = 5;
+/// How many upcoming runs `/cron-check` lists.
+const CRON_CHECK_RUNS: usize = 5;
+/// How much of a light's error the group results show.
+const GROUP_ERROR_LENGTH: usize = 200;
+const DEFAULT_HISTORY_COUNT: usize = 10;
+/// Most entries `/history` shows, which keeps the embed within Discord's limits.
+const MAX_HISTORY_COUNT: u64 = 25;
+/// Commands that act on the light, and so count towards `/stats`.
+const LIGHT_ACTION_COMMANDS: &[&str] = &[
+    TOGGLE_LIGHT_COMMAND,
+    "sunrise",
+    "locate",
+    "notify",
+    "temp-bright",
+];
+/// Commands that never reach the device, so they don't use up the rate limit.
+const NON_DEVICE_COMMANDS: &[&str] = &[
+    "whoami",
+    "uptime",
+    "stats",
+    "history",
+    "drift",
+    "cron-check",
+    "schedule",
+    "jobs",
+    "config",
+    "dnd",
+    "timers",
+    "skip-next",
+    "shift",
+    "pause",
+    "at",
+    "rename-channel",
+    "setup",
+];
+const REBOOT_CONFIRM_ID: &str = "reboot_confirm";
+const REBOOT_CANCEL_ID: &str = "reboot_cancel";
+const WIFI_JOIN_CONFIRM_ID: &str = "wifi_join_confirm";
+const WIFI_JOIN_CANCEL_ID: &str = "wifi_join_cancel";
+/// TP-Link's key type for WPA2, the usual home network security.
+const DEFAULT_WIFI_KEY_TYPE: i64 = 3;
+const DIMMER_DOWN_ID: &str = "dimmer_down";
+const DIMMER_UP_ID: &str = "dimmer_up";
+const DIMMER_VALUE_ID: &str = "dimmer_value";
+/// How far each `/dimmer` press moves the brightness.
+const DIMMER_STEP: u8 = 10;
+const REBOOT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(120);
+/// Each group gets an on and off button, two groups to a row, in the two rows
+/// Discord allows below the fixed controls.
+const MAX_DEVICE_GROUPS: usize = 4;
+/// The brightness menu takes one of the group rows.
+const MAX_DEVICE_GROUPS_WITH_SELECT: usize = 2;
+const BRIGHTNESS_SELECT_ID: &str = "brightness_select";
+/// Reactions on the pinned reaction controls message.
+const REACTION_ON: &str = "💡";
+const REACTION_OFF: &str = "🌙";
+const TOGGLE_LIGHT_COMMAND: &str = "Toggle Light";
+const DEFAULT_OFF_SCHEDULE: &str = "00:00";
+const DEFAULT_ON_SCHEDULE: &str = "17:00";
+const DEFAULT_LED_ON_SCHEDULE: &str = "07:00";
+const LOCATE_FLASHES: usize = 2;
+const LOCATE_FLASH_DELAY: Duration = Duration::from_millis(750);
+const MAX_NOTIFY_BLINKS: u64 = 10;
+const MAX_SNAPSHOT_NAME_LENGTH: u16 = 50;
+const MAX_TEMP_BRIGHTNESS_MINUTES: u64 = 12 * 60;
+const DISCORD_MESSAGE_LIMIT: usize = 2000;
+/// Longest name Discord allows for a command option choice.
+const DISCORD_CHOICE_NAME_LIMIT: usize = 100;
+const DEFAULT_RECONCILE_INTERVAL_SECS: u64 = 300;
+/// How often bulbs step along `COLOR_TEMP_CURVE`.
+const COLOR_TEMP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_SUNRISE_DURATION_MINUTES: u64 = 30;
+const DEFAULT_SUNRISE_STEPS: u32 = 20;
+const DEFAULT_TIMER_DIM_SECS: u64 = 60;
+const DEFAULT_TIMER_DIM_STEPS: u32 = 6;
+const DEFAULT_PRESERVE_BRIGHTNESS_WINDOW_MINUTES: u64 = 60;
+const DEFAULT_KASA_RETRIES: u32 = 2;
+const DEFAULT_SLOW_COMMAND_THRESHOLD_MS: u64 = 3000;
+const KASA_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// How long a kasa command may take unless the device sets its own timeout.
+const DEFAULT_KASA_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_FAILURE_NOTIFY_THRESHOLD: u32 = 1;
+const FEEDBACK_REACTION_DURATION: Duration = Duration::from_secs(5);
+const DEFAULT_RATE_LIMIT_BURST: u32 = 5;
+const DEFAULT_RATE_LIMIT_PER_MINUTE: u32 = 20;
+/// How long interaction ids are remembered to spot redeliveries.
+const INTERACTION_DEDUP_WINDOW: Duration = Duration::from_secs(5 * 60);
+const RATE_LIMITED_MESSAGE: &str = "The light is getting a lot of requests, try again shortly.";
+const STARTUP_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const STARTUP_MAX_BACKOFF: Duration = Duration::from_secs(300);
+const NO_LED_FEATURE_ERROR: &str = "This device has no controllable status LED";
+const NO_ON_TIME_FEATURE_ERROR: &str = "This device doesn't report how long it's been on";
+const NO_NIGHTLIGHT_FEATURE_ERROR: &str = "This device has no nightlight";
+const SECONDARY_FAILED_MESSAGE: &str = "The secondary light didn't follow, though.";
+
+/// Extracts the value printed by `kasa feature <name>`, which looks like
+/// `State (state): True`.
+fn parse_feature_value<'a>(stdout: &'a str, name: &str) -> Option<&'a str> {
+    let marker = format!("({}): ", name);
+    stdout
+        .lines()
+        .find_map(|line| line.split_once(&marker).map(|(_, value)| value.trim()))
+}
+
+/// Parses a datetime printed by python-kasa, e.g. `2024-05-01 17:02:13.123456+00:00`.
+/// Values without an offset are in the device's local time, `tz`.
+fn parse_device_datetime(value: &str, tz: Tz) -> Option<DateTime<Utc>> {
+    if let Ok(datetime) = DateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f%:z") {
+        return Some(datetime.with_timezone(&Utc));
+    }
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f")
+        .ok()?
+        .and_local_timezone(tz)
+        .earliest()
+        .map(|datetime| datetime.with_timezone(&Utc))
+}
+
+/// Formats a duration as e.g. `2h 13m`.
+fn format_duration(duration: chrono::Duration) -> String {
+    let minutes = duration.num_minutes().max(0);
+    match (minutes / 60, minutes % 60) {
+        (0, minutes) => format!("{}m", minutes),
+        (hours, minutes) => format!("{}h {}m", hours, minutes),
+    }
+}
+
+/// Builds a followup for `content`, moving it into a `.txt` attachment when it
+/// doesn't fit within Discord's message length limit.
+fn followup_with_content(content: String) -> CreateInteractionResponseFollowup {
+    if content.chars().count() <= DISCORD_MESSAGE_LIMIT {
+        return CreateInteractionResponseFollowup::new().content(content);
+    }
+
+    CreateInteractionResponseFollowup::new()
+        .content("The output was too long for a message, so it's attached as a file.")
+        .add_file(CreateAttachment::bytes(content.into_bytes(), "output.txt"))
+}
+
+/// Lists how each light in a group switch went.
+fn group_results_embed(
+    group: &DeviceGroup,
+    on: bool,
+    results: &[Result<(), String>],
+) -> CreateEmbed {
+    let lines: Vec<String> = group
+        .devices
+        .iter()
+        .zip(results)
+        .map(|(device, result)| match result {
+            Ok(()) => format!("✅ **{}**", device.name),
+            Err(e) => {
+                let e: String = e.chars().take(GROUP_ERROR_LENGTH).collect();
+                format!("❌ **{}**: {}", device.name, e)
+            }
+        })
+        .collect();
+    CreateEmbed::new()
+        .title(format!("{} {}", group.name, if on { "on" } else { "off" }))
+        .description(lines.join("\n"))
+}
+
+/// An immediate ephemeral reply for interactions that won't be processed.
+fn rejection_response(content: String) -> CreateInteractionResponse {
+    CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .content(content)
+            .ephemeral(true),
+    )
+}
+
+/// The rows of buttons shown on the control message, with a brightness menu
+/// under the fixed controls if `brightness_select` is set.
+fn control_panel_components(
+    groups: &[DeviceGroup],
+    brightness_select: bool,
+) -> Vec<CreateActionRow> {
+    let mut rows = vec![
+        CreateActionRow::Buttons(vec![
+            CreateButton::new("light_on")
+                .label("Turn On")
+                .style(ButtonStyle::Success),
+            CreateButton::new("light_off")
+                .label("Turn Off")
+                .style(ButtonStyle::Danger),
+        ]),
+        CreateActionRow::Buttons(vec![
+            CreateButton::new("light_on_15")
+                .label("15 min")
+                .style(ButtonStyle::Secondary),
+            CreateButton::new("light_on_30")
+                .label("30 min")
+                .style(ButtonStyle::Secondary),
+            CreateButton::new("light_on_60")
+                .label("60 min")
+                .style(ButtonStyle::Secondary),
+        ]),
+        CreateActionRow::Buttons(vec![
+            CreateButton::new("led_on")
+                .label("LED On")
+                .style(ButtonStyle::Secondary),
+            CreateButton::new("led_off")
+                .label("LED Off")
+                .style(ButtonStyle::Secondary),
+        ]),
+    ];
+    if brightness_select {
+        let options = (1..=10)
+            .map(|step| {
+                let percent = step * 10;
+                CreateSelectMenuOption::new(format!("{}%", percent), percent.to_string())
+            })
+            .collect();
+        rows.push(CreateActionRow::SelectMenu(
+            CreateSelectMenu::new(
+                BRIGHTNESS_SELECT_ID,
+                CreateSelectMenuKind::String { options },
+            )
+            .placeholder("Brightness"),
+        ));
+    }
+    let group_buttons: Vec<[CreateButton; 2]> = groups
+        .iter()
+        .enumerate()
+        .map(|(index, group)| {
+            [
+                CreateButton::new(format!("group_on_{}", index))
+                    .label(format!("{} On", group.name))
+                    .style(ButtonStyle::Success),
+                CreateButton::new(format!("group_off_{}", index))
+                    .label(format!("{} Off", group.name))
+                    .style(ButtonStyle::Danger),
+            ]
+        })
+        .collect();
+    rows.extend(
+        group_buttons
+            .chunks(2)
+            .map(|chunk| CreateActionRow::Buttons(chunk.iter().flatten().cloned().collect())),
+    );
+    rows
+}
+
+/// The `/dimmer` buttons, showing `percent` between the two steps.
+fn dimmer_components(percent: u8) -> Vec<CreateActionRow> {
+    vec![CreateActionRow::Buttons(vec![
+        CreateButton::new(DIMMER_DOWN_ID)
+            .label("−")
+            .style(ButtonStyle::Secondary)
+            .disabled(percent <= 1),
+        CreateButton::new(DIMMER_VALUE_ID)
+            .label(format!("{}%", percent))
+            .style(ButtonStyle::Primary)
+            .disabled(true),
+        CreateButton::new(DIMMER_UP_ID)
+            .label("+")
+            .style(ButtonStyle::Secondary)
+            .disabled(percent >= 100),
+    ])]
+}
+
+/// The control panel button a control reaction stands for.
+fn reaction_control(emoji: &ReactionType) -> Option<&'static str> {
+    match emoji {
+        ReactionType::Unicode(emoji) if emoji == REACTION_ON => Some("light_on"),
+        ReactionType::Unicode(emoji) if emoji == REACTION_OFF => Some("light_off"),
+        _ => None,
+    }
+}
+
+/// Whether a Discord request failed because the bot lacks a permission or
+/// can't see the channel.
+fn is_missing_permissions(error: &serenity::Error) -> bool {
+    matches!(
+        error,
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response))
+            if matches!(response.error.code, DISCORD_MISSING_ACCESS | DISCORD_MISSING_PERMISSIONS)
+    )
+}
+
+/// Appends a note to `message` if the secondary light failed to follow the primary.
+fn with_secondary_result(message: String, secondary_error: Option<String>) -> String {
+    match secondary_error {
+        Some(e) => {
+            error!("Secondary light failed to mirror action: {}", e);
+            format!("{} {}", message, SECONDARY_FAILED_MESSAGE)
+        }
+        None => message,
+    }
+}
+
+/// How a control panel press turned out.
+#[derive(Debug)]
+enum ComponentOutcome {
+    Done(String),
+    /// The light was unreachable, so the action waits in the offline queue.
+    Queued(String),
+    Failed(String),
+}
+
+impl ComponentOutcome {
+    fn message(&self) -> &str {
+        match self {
+            Self::Done(message) | Self::Queued(message) | Self::Failed(message) => message,
+        }
+    }
+}
+
+impl From<Result<String, String>> for ComponentOutcome {
+    fn from(result: Result<String, String>) -> Self {
+        match result {
+            Ok(message) => Self::Done(message),
+            Err(message) => Self::Failed(message),
+        }
+    }
+}
+
+/// A choice of one of the scheduled jobs in `jobs`, labelled with when each
+/// runs, e.g. `on (Mon-Fri 17:00)`.
+fn scheduled_job_option(
+    description: &str,
+    schedules: &Schedules,
+    jobs: &[&str],
+) -> CreateCommandOption {
+    schedules
+        .jobs()
+        .into_iter()
+        .filter(|(job, _)| jobs.contains(job))
+        .fold(
+            CreateCommandOption::new(CommandOptionType::String, "job", description).required(true),
+            |option, (job, schedule)| {
+                let label: String = format!("{} ({})", job, schedule)
+                    .chars()
+                    .take(DISCORD_CHOICE_NAME_LIMIT)
+                    .collect();
+                option.add_string_choice(label, job)
+            },
+        )
+}
+
+/// The next time the clock reads `time`, e.g. `20:00`, in `tz`.
+fn next_local_time(time: &str, tz: Tz) -> Option<DateTime<Utc>> {
+    let time = NaiveTime::parse_from_str(time.trim(), "%H:%M").ok()?;
+    let now = Utc::now().with_timezone(&tz);
+    let today = now.date_naive().and_time(time);
+    [today, today + chrono::Duration::days(1)]
+        .into_iter()
+        .filter_map(|local| tz.from_local_datetime(&local).earliest())
+        .find(|at| *at > now)
+        .map(|at| at.with_timezone(&Utc))
+}
+
+/// Parses a signed offset like `+30m`, `-1h` or `+1h30m`. A missing sign
+/// means later.
+fn parse_offset(value: &str) -> Option<chrono::Duration> {
+    let value = value.trim();
+    let (sign, value) = match value.strip_prefix('-') {
+        Some(value) => (-1, value),
+        None => (1, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let (hours, minutes) = match value.split_once('h') {
+        Some((hours, minutes)) => (hours.parse::<i64>().ok()?, minutes),
+        None => (0, value),
+    };
+    let minutes = match minutes.strip_suffix('m') {
+        Some(minutes) => minutes.parse::<i64>().ok()?,
+        None if minutes.is_empty() && value.ends_with('h') => 0,
+        None => return None,
+    };
+    Some(chrono::Duration::minutes(sign * (hours * 60 + minutes)))
+}
+
+/// Reads a job schedule such as `Mon-Fri 17:00, Sat-Sun 10:00` from `key`.
+fn schedule_from_config(
+    config: ConfigSource,
+    key: &str,
+    default: &str,
+) -> Result<JobSchedule, String> {
+    config
+        .optional(key)
+        .as_deref()
+        .unwrap_or(default)
+        .parse()
+        .map_err(|e| format!("{key} is invalid: {e}"))
+}
+
+/// Reads an optional brightness percentage from `key`.
+fn brightness_from_config(config: ConfigSource, key: &str) -> Option<u8> {
+    config.optional(key).map(|value| {
+        value
+            .parse()
+            .ok()
+            .filter(|percent| (1..=100).contains(percent))
+            .unwrap_or_else(|| panic!("{key} must be a percentage from 1 to 100, got {value}"))
+    })
+}
+
+/// Picks one job's schedule out of [`Schedules`].
+type ScheduleFn = fn(&Schedules) -> &JobSchedule;
+
+/// When each scheduled job runs. Shared so a config reload can change it.
+#[derive(Clone)]
+struct Schedules {
+    off: JobSchedule,
+    on: JobSchedule,
+    led_on: JobSchedule,
+    /// Extra actions from `SCHEDULED_ACTIONS`.
+    actions: Vec<ScheduledAction>,
+    /// Windows from `BLACKOUT_WINDOWS` in which no scheduled job runs.
+    blackouts: Vec<BlackoutWindow>,
+}
+
+impl Schedules {
+    fn from_config(config: ConfigSource) -> Result<Self, String> {
+        Ok(Self {
+            off: schedule_from_config(config, "OFF_SCHEDULE", DEFAULT_OFF_SCHEDULE)?,
+            on: schedule_from_config(config, "ON_SCHEDULE", DEFAULT_ON_SCHEDULE)?,
+            led_on: schedule_from_config(config, "LED_ON_SCHEDULE", DEFAULT_LED_ON_SCHEDULE)?,
+            actions: match config.optional("SCHEDULED_ACTIONS") {
+                Some(value) => parse_scheduled_actions(&value)
+                    .map_err(|e| format!("SCHEDULED_ACTIONS is invalid: {e}"))?,
+                None => Vec::new(),
+            },
+            blackouts: match config.optional("BLACKOUT_WINDOWS") {
+                Some(value) => parse_blackout_windows(&value)
+                    .map_err(|e| format!("BLACKOUT_WINDOWS is invalid: {e}"))?,
+                None => Vec::new(),
+            },
+        })
+    }
+
+    /// The scheduled jobs, by name.
+    fn jobs(&self) -> [(&'static str, &JobSchedule); 3] {
+        [("off", &self.off), ("on", &self.on), ("led", &self.led_on)]
+    }
+}
+
+/// Parses `GUILD_TIMEZONES`, e.g. `123456789=America/Vancouver,987654321=Europe/London`.
+fn parse_guild_timezones(value: &str) -> HashMap<GuildId, Tz> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let parsed = entry.split_once('=').and_then(|(guild_id, tz)| {
+                Some((
+                    GuildId::new(guild_id.trim().parse().ok()?),
+                    tz.trim().parse().ok()?,
+                ))
+            });
+            parsed.unwrap_or_else(|| {
+                panic!("GUILD_TIMEZONES entries must look like <guild id>=<timezone>, got {entry}")
+            })
+        })
+        .collect()
+}
+
+/// Parses `COMMAND_CHANNELS`, a comma-separated list of channel ids.
+fn parse_command_channels(value: &str) -> HashSet<ChannelId> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            ChannelId::new(id.parse().unwrap_or_else(|_| {
+                panic!("COMMAND_CHANNELS must be a list of Discord channel ids, got {id}")
+            }))
+        })
+        .collect()
+}
+
+/// Normalizes a channel name the way Discord does and checks it's one
+/// Discord accepts for a text channel.
+fn validate_channel_name(name: &str) -> Result<String, String> {
+    let name = name.trim().to_lowercase().replace(' ', "-");
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err("Channel names must be 1 to 100 characters long.".to_string());
+    }
+    if let Some(invalid) = name
+        .chars()
+        .find(|&c| !(c.is_alphanumeric() || c == '-' || c == '_'))
+    {
+        return Err(format!(
+            "Channel names can only contain letters, numbers, - and _, not {}.",
+            invalid
+        ));
+    }
+    Ok(name)
+}
+
+/// A job added to the scheduler, kept for `/jobs`.
+struct RegisteredJob {
+    id: Uuid,
+    name: String,
+    /// The cron expression or interval the scheduler fires the job on.
+    trigger: String,
+    /// The schedule the job checks on each tick, and the timezone it's in.
+    schedule: Option<(Tz, ScheduleFn)>,
+}
+
+/// A command or button someone used.
+struct LastCommand {
+    name: String,
+    at: DateTime<Utc>,
+}
+
+/// A network the owner asked the light to join, waiting for confirmation.
+struct WifiJoin {
+    ssid: String,
+    password: String,
+    key_type: i64,
+}
+
+/// How late a scheduled job fired.
+struct DriftSample {
+    job: String,
+    scheduled: DateTime<Utc>,
+    drift: chrono::Duration,
+}
+
+/// An action from `/at` waiting to run.
+struct OneShot {
+    id: Uuid,
+    at: DateTime<Utc>,
+    action: Action,
+}
+
+/// A timed turn-on that hasn't finished yet.
+struct ActiveTimer {
+    device_name: String,
+    ends_at: DateTime<Utc>,
+    /// Dims and turns the light off at the end, if dimming is enabled.
+    dim_task: Option<JoinHandle<()>>,
+}
+
+/// A brightness to put back the next time a light turns on.
+#[derive(Clone, Copy)]
+struct RememberedBrightness {
+    percent: u8,
+    /// When to forget it, if ever.
+    expires: Option<Instant>,
+}
+
+/// A temporary brightness that reverts when its task finishes.
+struct TempBrightness {
+    restore_to: u8,
+    task: JoinHandle<()>,
+}
+
+#[derive(Clone)]
+struct Handler {
+    control_channels: Arc<RwLock<HashMap<GuildId, ChannelId>>>,
+    /// What the control channels are called, so they can be found again.
+    control_channel_name: Arc<std::sync::RwLock<String>>,
+    /// The control panel message posted in each channel.
+    panel_messages: Arc<RwLock<HashMap<ChannelId, MessageId>>>,
+    /// `PANEL_TEMPLATE`, the panel's text with `{device_name}` and `{state}`
+    /// placeholders.
+    panel_template: Option<String>,
+    /// Channels besides the control channel where interactions are accepted.
+    /// Empty means anywhere.
+    command_channels: HashSet<ChannelId>,
+    light_state: Arc<RwLock<Option<bool>>>,
+    /// The light's on-time, for the metrics endpoint.
+    on_time: Arc<OnTimeMetrics>,
+    /// Where to serve Prometheus metrics, if anywhere.
+    metrics_addr: Option<String>,
+    reconcile_interval: Option<Duration>,
+    reconcile_on_start: bool,
+    responses_ephemeral: bool,
+    sunrise_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    sunrise_duration: Duration,
+    sunrise_steps: u32,
+    /// Timed turn-ons in progress, by host.
+    timers: Arc<Mutex<HashMap<String, ActiveTimer>>>,
+    timer_dim: Option<Duration>,
+    timer_dim_steps: u32,
+    /// Brightness to restore on the next turn-on, by host, for lights a timer
+    /// dimmed before turning them off, or that were turned off while
+    /// `preserve_brightness` is set. Entries with an expiry are dropped after it.
+    restore_brightness: Arc<RwLock<HashMap<String, RememberedBrightness>>>,
+    /// Temporary brightnesses set with `/temp-bright`, by host.
+    temp_brightness: Arc<Mutex<HashMap<String, TempBrightness>>>,
+    /// Recently handled interactions and when they arrived.
+    seen_interactions: Arc<Mutex<HashMap<InteractionId, Instant>>>,
+    /// Button presses that failed while the light was unreachable, applied
+    /// by the reconcile job once it's back.
+    offline_queue: Arc<Mutex<Vec<(Action, Instant)>>>,
+    /// How long queued actions stay valid. `None` disables the queue.
+    offline_queue_ttl: Option<Duration>,
+    /// How long a light's brightness is remembered after turning it off, if
+    /// it should be restored on the next turn-on.
+    preserve_brightness: Option<Duration>,
+    /// Brightness the midnight job resets the light to, so the next turn-on
+    /// starts fresh.
+    midnight_brightness: Option<u8>,
+    /// Longest the light stays on without a timer, using the device's auto-off.
+    max_on_minutes: Option<u32>,
+    /// Switch the nightlight on when the midnight job turns the light off.
+    midnight_nightlight: bool,
+    /// Show a brightness menu on the control panel.
+    brightness_select: bool,
+    /// Also post a pinned message that switches the light with reactions.
+    reaction_controls: bool,
+    /// The reaction controls messages that are listened to.
+    reaction_messages: Arc<RwLock<HashSet<MessageId>>>,
+    /// Brightness the scheduled on job sets after turning the light on.
+    schedule_on_brightness: Option<u8>,
+    /// The light this handler targets.
+    device: Device,
+    /// The light that mirrors `device`'s actions, if any.
+    secondary_device: Option<Device>,
+    groups: Vec<DeviceGroup>,
+    controller: Arc<dyn LightController>,
+    owner_id: Option<UserId>,
+    /// `/wifi join` requests awaiting the owner's confirmation, by who asked.
+    pending_wifi_joins: Arc<std::sync::Mutex<HashMap<UserId, WifiJoin>>>,
+    control_role_id: Option<RoleId>,
+    /// The latest connection's context, replaced on each ready.
+    discord: Arc<std::sync::RwLock<Option<Context>>>,
+    kasa_retries: u32,
+    /// How long each kasa attempt may take, for devices without their own timeout.
+    kasa_timeout: Duration,
+    slow_command_threshold: Duration,
+    consecutive_failures: Arc<AtomicU32>,
+    failure_notify_threshold: u32,
+    failure_notified: Arc<AtomicBool>,
+    started: Arc<AtomicBool>,
+    /// Set on each ready, so a client that stops after connecting can be told
+    /// apart from one that never connected.
+    connected: Arc<AtomicBool>,
+    /// Set with `/dnd`, stops the bot posting anything on its own.
+    do_not_disturb: Arc<AtomicBool>,
+    rate_limiter: Arc<RateLimiter>,
+    units: Units,
+    brightness_curve: Option<BrightnessCurve>,
+    color_temp_curve: Option<ColorTempCurve>,
+    /// `QUIET_HOURS` and the highest brightness allowed during them.
+    quiet_hours: Option<(QuietHours, u8)>,
+    usage_stats: Arc<UsageStats>,
+    history: Arc<History>,
+    snapshots: Arc<SnapshotStore>,
+    translations: Arc<Translations>,
+    /// Skip the scheduled turn-on when nobody with the control role is online.
+    away_detection: bool,
+    /// Skip the scheduled turn-on while a light sensor says it's bright.
+    darkness_check: Option<DarknessCheck>,
+    /// Users who are currently online, by guild. Only kept with away detection.
+    online_users: Arc<RwLock<HashMap<GuildId, HashSet<UserId>>>>,
+    state_webhook: Option<Arc<StateWebhook>>,
+    /// Webhooks fired when an action succeeds, by `on`, `off` or `brightness`.
+    action_webhooks: HashMap<&'static str, Arc<ActionWebhook>>,
+    schedules: Arc<std::sync::RwLock<Schedules>>,
+    timezone: Tz,
+    guild_timezones: HashMap<GuildId, Tz>,
+    skipped_jobs: Arc<RwLock<HashMap<&'static str, u32>>>,
+    /// How long before the on and off jobs to warn the control channels.
+    announce_before: Option<chrono::Duration>,
+    /// When someone last switched the light from Discord.
+    last_manual_action: Arc<RwLock<Option<Instant>>>,
+    schedule_precedence: SchedulePrecedence,
+    /// How long a manual change counts as recent for `schedule_precedence`.
+    manual_override_window: Duration,
+    job_scheduler: Arc<OnceLock<JobScheduler>>,
+    registered_jobs: Arc<std::sync::RwLock<Vec<RegisteredJob>>>,
+    one_shots: Arc<Mutex<Vec<OneShot>>>,
+    /// Jobs turned off with `/schedule disable`, saved as `DISABLED_JOBS`.
+    disabled_jobs: Arc<std::sync::RwLock<HashSet<String>>>,
+    started_at: Instant,
+    /// The last command or button someone used, and when, for `/uptime`.
+    last_command: Arc<RwLock<Option<LastCommand>>>,
+    /// The latest scheduled fires and how late they were, for `/drift`.
+    drift_samples: Arc<std::sync::Mutex<VecDeque<DriftSample>>>,
+    /// Scheduled jobs don't run until this time, set with `/pause`.
+    paused_until: Arc<RwLock<Option<DateTime<Utc>>>>,
+}
+
+impl Handler {
+    fn new() -> Self {
+        let device = Device::primary_from_env();
+        let secondary_device = Device::secondary_from_env(&device.credentials);
+        Self::with_controller(
+            device,
+            secondary_device,
+            Arc::new(KasaCliController::from_env()),
+            ConfigSource::env(),
+        )
+    }
+
+    fn with_controller(
+        device: Device,
+        secondary_device: Option<Device>,
+        controller: Arc<dyn LightController>,
+        config: ConfigSource,
+    ) -> Self {
+        // An interval of zero disables reconciliation
+        let reconcile_interval =
+            config.parsed("RECONCILE_INTERVAL_SECS", DEFAULT_RECONCILE_INTERVAL_SECS);
+        let reconcile_interval =
+            (reconcile_interval > 0).then(|| Duration::from_secs(reconcile_interval));
+        // Queued actions are applied by the reconcile job, so it needs to run
+        let offline_queue_minutes: u64 = config.parsed("OFFLINE_QUEUE_MINUTES", 0);
+        let offline_queue_ttl =
+            (offline_queue_minutes > 0).then(|| Duration::from_secs(60 * offline_queue_minutes));
+        if offline_queue_ttl.is_some() && reconcile_interval.is_none() {
+            panic!("OFFLINE_QUEUE_MINUTES needs RECONCILE_INTERVAL_SECS to be above zero");
+        }
+        let sunrise_minutes =
+            config.parsed("SUNRISE_DURATION_MINUTES", DEFAULT_SUNRISE_DURATION_MINUTES);
+        let sunrise_steps = config.parsed("SUNRISE_STEPS", DEFAULT_SUNRISE_STEPS).max(1);
+        // A dim duration of zero snaps the light off at the end of the timer
+        let timer_dim = config.parsed("TIMER_DIM_SECS", DEFAULT_TIMER_DIM_SECS);
+        let timer_dim = (timer_dim > 0).then(|| Duration::from_secs(timer_dim));
+        let control_role_id =
+            config.optional("CONTROL_ROLE_ID").map(|id| {
+                RoleId::new(id.parse().unwrap_or_else(|_| {
+                    panic!("CONTROL_ROLE_ID must be a Discord role id, got {id}")
+                }))
+            });
+        let owner_id = config.optional("OWNER_ID").map(|id| {
+            UserId::new(
+                id.parse()
+                    .unwrap_or_else(|_| panic!("OWNER_ID must be a Discord user id, got {id}")),
+            )
+        });
+
+        let known_devices: Vec<Device> = std::iter::once(device.clone())
+            .chain(secondary_device.clone())
+            .collect();
+        let groups = config
+            .optional("DEVICE_GROUPS")
+            .map(|value| {
+                parse_device_groups(&value, &known_devices)
+                    .unwrap_or_else(|e| panic!("DEVICE_GROUPS is invalid: {e}"))
+            })
+            .unwrap_or_default();
+        if groups.len() > MAX_DEVICE_GROUPS {
+            panic!("DEVICE_GROUPS can have at most {MAX_DEVICE_GROUPS} groups");
+        }
+        let brightness_select = config.bool("BRIGHTNESS_SELECT", false);
+        if brightness_select && groups.len() > MAX_DEVICE_GROUPS_WITH_SELECT {
+            panic!(
+                "DEVICE_GROUPS can have at most {MAX_DEVICE_GROUPS_WITH_SELECT} groups with BRIGHTNESS_SELECT"
+            );
+        }
+
+        Self {
+            control_channels: Arc::new(RwLock::new(HashMap::new())),
+            control_channel_name: Arc::new(std::sync::RwLock::new(
+                config
+                    .optional("CONTROL_CHANNEL_NAME")
+                    .unwrap_or_else(|| DEFAULT_CONTROL_CHANNEL_NAME.to_string()),
+            )),
+            panel_messages: Arc::new(RwLock::new(HashMap::new())),
+            panel_template: config.optional("PANEL_TEMPLATE"),
+            command_channels: config
+                .optional("COMMAND_CHANNELS")
+                .map(|value| parse_command_channels(&value))
+                .unwrap_or_default(),
+            light_state: Arc::new(RwLock::new(None)),
+            on_time: Arc::default(),
+            metrics_addr: config.optional("METRICS_ADDR"),
+            reconcile_interval,
+            reconcile_on_start: config.bool("RECONCILE_ON_START", false),
+            responses_ephemeral: config.bool("RESPONSES_EPHEMERAL", true),
+            sunrise_task: Arc::new(Mutex::new(None)),
+            sunrise_duration: Duration::from_secs(sunrise_minutes * 60),
+            sunrise_steps,
+            timers: Arc::new(Mutex::new(HashMap::new())),
+            timer_dim,
+            timer_dim_steps: config
+                .parsed("TIMER_DIM_STEPS", DEFAULT_TIMER_DIM_STEPS)
+                .max(1),
+            restore_brightness: Arc::new(RwLock::new(HashMap::new())),
+            temp_brightness: Arc::new(Mutex::new(HashMap::new())),
+            seen_interactions: Arc::new(Mutex::new(HashMap::new())),
+            offline_queue: Arc::new(Mutex::new(Vec::new())),
+            offline_queue_ttl,
+            preserve_brightness: config.bool("PRESERVE_BRIGHTNESS", false).then(|| {
+                Duration::from_secs(
+                    60 * config.parsed(
+                        "PRESERVE_BRIGHTNESS_WINDOW_MINUTES",
+                        DEFAULT_PRESERVE_BRIGHTNESS_WINDOW_MINUTES,
+                    ),
+                )
+            }),
+            midnight_brightness: brightness_from_config(config, "MIDNIGHT_RESET_BRIGHTNESS"),
+            midnight_nightlight: config.bool("MIDNIGHT_NIGHTLIGHT", false),
+            brightness_select,
+            reaction_controls: config.bool("REACTION_CONTROLS", false),
+            reaction_messages: Arc::default(),
+            max_on_minutes: config.optional("MAX_ON_MINUTES").map(|minutes| {
+                minutes
+                    .parse()
+                    .ok()
+                    .filter(|minutes| (1..=MAX_AUTO_OFF_MINUTES as u32).contains(minutes))
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "MAX_ON_MINUTES must be from 1 to {}, got {}",
+                            MAX_AUTO_OFF_MINUTES, minutes
+                        )
+                    })
+            }),
+            color_temp_curve: config.optional("COLOR_TEMP_CURVE").map(|curve| {
+                curve
+                    .parse()
+                    .unwrap_or_else(|e| panic!("COLOR_TEMP_CURVE is invalid: {e}"))
+            }),
+            quiet_hours: config.optional("QUIET_HOURS").map(|hours| {
+                let hours = hours
+                    .parse()
+                    .unwrap_or_else(|e| panic!("QUIET_HOURS is invalid: {e}"));
+                let max = brightness_from_config(config, "QUIET_MAX_BRIGHTNESS")
+                    .unwrap_or_else(|| panic!("QUIET_HOURS needs QUIET_MAX_BRIGHTNESS"));
+                (hours, max)
+            }),
+            schedule_on_brightness: brightness_from_config(config, "SCHEDULE_ON_BRIGHTNESS"),
+            device,
+            secondary_device,
+            groups,
+            controller,
+            owner_id,
+            pending_wifi_joins: Arc::default(),
+            control_role_id,
+            discord: Arc::default(),
+            kasa_retries: config.parsed("KASA_RETRIES", DEFAULT_KASA_RETRIES),
+            kasa_timeout: timeout_from_config(config, "KASA_TIMEOUT_SECS")
+                .unwrap_or(Duration::from_secs(DEFAULT_KASA_TIMEOUT_SECS)),
+            slow_command_threshold: Duration::from_millis(config.parsed(
+                "SLOW_COMMAND_THRESHOLD_MS",
+                DEFAULT_SLOW_COMMAND_THRESHOLD_MS,
+            )),
+            consecutive_failures: Arc::new(AtomicU32::new(0)),
+            failure_notify_threshold: config
+                .parsed("FAILURE_NOTIFY_THRESHOLD", DEFAULT_FAILURE_NOTIFY_THRESHOLD)
+                .max(1),
+            failure_notified: Arc::new(AtomicBool::new(false)),
+            started: Arc::new(AtomicBool::new(false)),
+            connected: Arc::new(AtomicBool::new(false)),
+            do_not_disturb: Arc::new(AtomicBool::new(false)),
+            rate_limiter: Arc::new(RateLimiter::new(
+                config.positive("RATE_LIMIT_BURST", DEFAULT_RATE_LIMIT_BURST),
+                config.positive("RATE_LIMIT_PER_MINUTE", DEFAULT_RATE_LIMIT_PER_MINUTE),
+                Duration::from_secs(60),
+            )),
+            units: config.parsed("UNITS", Units::Metric),
+            brightness_curve: config.optional("BRIGHTNESS_CURVE").map(|curve| {
+                curve
+                    .parse()
+                    .unwrap_or_else(|e| panic!("BRIGHTNESS_CURVE is invalid: {e}"))
+            }),
+            state_webhook: config
+                .optional("STATE_WEBHOOK_URL")
+                .map(|url| Arc::new(StateWebhook::new(url))),
+            action_webhooks: ["on", "off", "brightness"]
+                .into_iter()
+                .filter_map(|action| {
+                    let prefix = action.to_ascii_uppercase();
+                    let url = config.optional(&format!("{prefix}_WEBHOOK_URL"))?;
+                    let template = config.optional(&format!("{prefix}_WEBHOOK_TEMPLATE"));
+                    let webhook = ActionWebhook::new(url, template)
+                        .unwrap_or_else(|e| panic!("{prefix}_WEBHOOK_TEMPLATE is invalid: {e}"));
+                    Some((action, Arc::new(webhook)))
+                })
+                .collect(),
+            away_detection: config.bool("AWAY_DETECTION", false),
+            darkness_check: DarknessCheck::from_config(config),
+            online_users: Arc::new(RwLock::new(HashMap::new())),
+            history: Arc::new(History::load(
+                config
+                    .optional("HISTORY_FILE")
+                    .map(std::path::PathBuf::from),
+                config.parsed("HISTORY_SIZE", DEFAULT_HISTORY_SIZE).max(1),
+            )),
+            usage_stats: Arc::new(UsageStats::load(
+                config
+                    .optional("STATS_FILE")
+                    .unwrap_or_else(|| DEFAULT_STATS_FILE.to_string()),
+            )),
+            snapshots: Arc::new(SnapshotStore::load(
+                config
+                    .optional("SNAPSHOTS_FILE")
+                    .unwrap_or_else(|| DEFAULT_SNAPSHOTS_FILE.to_string()),
+            )),
+            translations: Arc::new(
+                Translations::load(config.optional("TRANSLATIONS_FILE").as_deref())
+                    .unwrap_or_else(|e| panic!("{e}")),
+            ),
+            schedules: Arc::new(std::sync::RwLock::new(
+                Schedules::from_config(config).unwrap_or_else(|e| panic!("{e}")),
+            )),
+            timezone: config.parsed("TIMEZONE", Toronto),
+            guild_timezones: config
+                .optional("GUILD_TIMEZONES")
+                .map(|value| parse_guild_timezones(&value))
+                .unwrap_or_default(),
+            skipped_jobs: Arc::new(RwLock::new(HashMap::new())),
+            announce_before: config.optional("ANNOUNCE_MINUTES").map(|value| {
+                let minutes: i64 = value
+                    .parse()
+                    .ok()
+                    .filter(|&minutes| minutes > 0)
+                    .unwrap_or_else(|| {
+                        panic!("ANNOUNCE_MINUTES must be a positive number of minutes, got {value}")
+                    });
+                chrono::Duration::minutes(minutes)
+            }),
+            last_manual_action: Arc::new(RwLock::new(None)),
+            schedule_precedence: config.parsed("SCHEDULE_PRECEDENCE", SchedulePrecedence::Schedule),
+            manual_override_window: Duration::from_secs(
+                60 * config.parsed("MANUAL_OVERRIDE_MINUTES", DEFAULT_MANUAL_OVERRIDE_MINUTES),
+            ),
+            job_scheduler: Arc::new(OnceLock::new()),
+            registered_jobs: Arc::new(std::sync::RwLock::new(Vec::new())),
+            one_shots: Arc::new(Mutex::new(Vec::new())),
+            disabled_jobs: Arc::new(std::sync::RwLock::new(
+                config
+                    .optional("DISABLED_JOBS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|job| !job.is_empty())
+                    .map(|job| {
+                        if !SCHEDULED_JOBS.contains(&job) {
+                            panic!("DISABLED_JOBS names unknown job {job}");
+                        }
+                        job.to_string()
+                    })
+                    .collect(),
+            )),
+            started_at: Instant::now(),
+            last_command: Arc::new(RwLock::new(None)),
+            drift_samples: Arc::default(),
+            paused_until: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// The timezone a guild's schedule runs in, falling back to `TIMEZONE`.
+    fn guild_timezone(&self, guild_id: Option<GuildId>) -> Tz {
+        guild_id
+            .and_then(|guild_id| self.guild_timezones.get(&guild_id).copied())
+            .unwrap_or(self.timezone)
+    }
+
+    /// The timezones the schedule runs in, each with the guilds that use it.
+    /// Guilds sharing a timezone share one set of jobs so the light isn't
+    /// switched twice.
+    async fn schedule_timezones(&self) -> Vec<(Tz, Vec<GuildId>)> {
+        let mut timezones: Vec<(Tz, Vec<GuildId>)> = Vec::new();
+        for &guild_id in self.control_channels.read().await.keys() {
+            let tz = self.guild_timezone(Some(guild_id));
+            match timezones.iter_mut().find(|(existing, _)| *existing == tz) {
+                Some((_, guilds)) => guilds.push(guild_id),
+                None => timezones.push((tz, vec![guild_id])),
+            }
+        }
+        if timezones.is_empty() {
+            timezones.push((self.timezone, Vec::new()));
+        }
+        timezones
+    }
+
+    /// Records an interaction, returning false if it was already delivered
+    /// recently.
+    async fn first_delivery(&self, id: InteractionId) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen_interactions.lock().await;
+        seen.retain(|_, arrived| now.duration_since(*arrived) < INTERACTION_DEDUP_WINDOW);
+        seen.insert(id, now).is_none()
+    }
+
+    fn control_channel_name(&self) -> String {
+        self.control_channel_name.read().unwrap().clone()
+    }
+
+    /// Whether interactions are accepted in a channel. The control channel
+    /// always is.
+    async fn channel_allowed(&self, guild_id: Option<GuildId>, channel_id: ChannelId) -> bool {
+        if self.command_channels.is_empty() || self.command_channels.contains(&channel_id) {
+            return true;
+        }
+        let control_channel = match guild_id {
+            Some(guild_id) => self.control_channels.read().await.get(&guild_id).copied(),
+            None => None,
+        };
+        control_channel == Some(channel_id)
+    }
+
+    /// Points the user at the control channel of the guild they're in.
+    async fn wrong_channel_message(&self, guild_id: Option<GuildId>) -> String {
+        let control_channel = match guild_id {
+            Some(guild_id) => self.control_channels.read().await.get(&guild_id).copied(),
+            None => None,
+        };
+        match control_channel {
+            Some(channel_id) => format!("Please use <#{}> for light controls.", channel_id),
+            None => format!(
+                "Please use #{} for light controls.",
+                self.control_channel_name()
+            ),
+        }
+    }
+
+    /// Whether a member may use the light controls. Everyone may unless
+    /// CONTROL_ROLE_ID is configured.
+    fn has_control_role(&self, member: Option<&Member>) -> bool {
+        match self.control_role_id {
+            Some(role_id) => member.is_some_and(|member| member.roles.contains(&role_id)),
+            None => true,
+        }
+    }
+
+    /// Translates a reply into the user's locale, leaving anything without a
+    /// translation in English.
+    fn localize(&self, locale: &str, content: &str) -> String {
+        content
+            .lines()
+            .map(|line| {
+                // The secondary light's failure is tacked onto other messages
+                match line.strip_suffix(SECONDARY_FAILED_MESSAGE) {
+                    Some(message) => format!(
+                        "{} {}",
+                        self.translations.translate(locale, message.trim_end()),
+                        self.translations
+                            .translate(locale, SECONDARY_FAILED_MESSAGE)
+                    ),
+                    None => self.translations.translate(locale, line).to_string(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Tells whoever turned the light on about the safety cutoff, if there is one.
+    fn with_safety_cutoff(&self, message: String) -> String {
+        match self.max_on_minutes {
+            Some(minutes) => format!(
+                "{}\nIt'll turn off after {} as a safety cutoff.",
+                message,
+                format_duration(chrono::Duration::minutes(minutes.into()))
+            ),
+            None => message,
+        }
+    }
+
+    fn missing_role_message(&self) -> String {
+        match self.control_role_id {
+            Some(role_id) => format!("You need the <@&{}> role to control the light.", role_id),
+            None => "You're not allowed to control the light.".to_string(),
+        }
+    }
+
+    async fn is_owner(&self, ctx: &Context, user_id: UserId) -> bool {
+        if let Some(owner_id) = self.owner_id {
+            return owner_id == user_id;
+        }
+        // Fall back to the owner of the bot application
+        match ctx.http.get_current_application_info().await {
+            Ok(info) => info.owner.is_some_and(|owner| owner.id == user_id),
+            Err(e) => {
+                error!("Failed to fetch application info: {:?}", e);
+                false
+            }
+        }
+    }
+
+    async fn register_commands(&self, ctx: &Context) {
+        // Only offer the nightlight on devices that have one
+        let has_nightlight = match self.query_sysinfo().await {
+            Ok(sysinfo) => sysinfo.has_nightlight(),
+            Err(e) => {
+                warn!("Couldn't check for a nightlight: {}", e);
+                false
+            }
+        };
+        // Job choices are labelled with their current schedules
+        let schedules = self.schedules();
+        let commands: Vec<CreateCommand> = vec![
+            CreateCommand::new("run-job")
+                .description("Run a scheduled job immediately (owner only)")
+                .add_option(scheduled_job_option(
+                    "Job to run",
+                    &schedules,
+                    &["off", "on", "led"],
+                )),
+            CreateCommand::new("status").description("Show the device's full status"),
+            CreateCommand::new(TOGGLE_LIGHT_COMMAND).kind(CommandType::Message),
+            CreateCommand::new("sunrise")
+                .description("Gradually brighten the light from 1% to 100%"),
+            CreateCommand::new("on-time").description("Show how long the light has been on"),
+            CreateCommand::new("locate").description("Flash the light to identify the device"),
+            CreateCommand::new("temp-bright")
+                .description("Change the brightness for a while, then change it back")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::Integer, "percent", "Brightness")
+                        .min_int_value(1)
+                        .max_int_value(100)
+                        .required(true),
+                )
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Integer,
+                        "minutes",
+                        "How long before changing it back",
+                    )
+                    .min_int_value(1)
+                    .max_int_value(MAX_TEMP_BRIGHTNESS_MINUTES)
+                    .required(true),
+                ),
+            CreateCommand::new("ping-device")
+                .description("Check that each light is reachable and how quickly it answers"),
+            CreateCommand::new("reload-credentials").description(
+                "Re-read the light's username and password and check they work (owner only)",
+            ),
+            CreateCommand::new("snapshot")
+                .description("Save the light's current setting or bring a saved one back")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::SubCommand,
+                        "save",
+                        "Save the light's current setting",
+                    )
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::String,
+                            "name",
+                            "Snapshot name",
+                        )
+                        .max_length(MAX_SNAPSHOT_NAME_LENGTH)
+                        .required(true),
+                    ),
+                )
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::SubCommand,
+                        "restore",
+                        "Bring back a saved setting",
+                    )
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::String,
+                            "name",
+                            "Snapshot name",
+                        )
+                        .max_length(MAX_SNAPSHOT_NAME_LENGTH)
+                        .required(true),
+                    ),
+                ),
+            CreateCommand::new("notify")
+                .description("Blink the light as a visual alert")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Integer,
+                        "count",
+                        "How many times to blink",
+                    )
+                    .min_int_value(1)
+                    .max_int_value(MAX_NOTIFY_BLINKS)
+                    .required(true),
+                ),
+            CreateCommand::new("reboot-device")
+                .description("Reboot the smart plug, briefly cutting power (owner only)"),
+            CreateCommand::new("jobs")
+                .description("List the scheduler's jobs and when they next fire (owner only)"),
+            CreateCommand::new("rename-channel")
+                .description("Rename the control channel (owner only)")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::String, "name", "New channel name")
+                        .max_length(100)
+                        .required(true),
+                ),
+            CreateCommand::new("config")
+                .description("Inspect the bot's configuration (owner only)")
+                .add_option(CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "dump",
+                    "Show the configuration the bot loaded, with credentials redacted",
+                )),
+            CreateCommand::new("autooff")
+                .description("Configure the device's own auto-off timer")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::SubCommand,
+                        "set",
+                        "Turn the light off automatically this long after it turns on",
+                    )
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::Integer,
+                            "minutes",
+                            "Minutes until auto-off, or 0 to disable it",
+                        )
+                        .min_int_value(0)
+                        .max_int_value(MAX_AUTO_OFF_MINUTES)
+                        .required(true),
+                    ),
+                )
+                .add_option(CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "show",
+                    "Show the device's auto-off setting",
+                )),
+            CreateCommand::new("timers")
+                .description("List or cancel running light timers")
+                .add_option(CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "list",
+                    "List running timers",
+                ))
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::SubCommand,
+                        "cancel",
+                        "Cancel a light's timer, leaving it on",
+                    )
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::String,
+                            "device",
+                            "Name of the light",
+                        )
+                        .required(true),
+                    ),
+                ),
+            CreateCommand::new("stats").description("Show who uses the light the most"),
+            CreateCommand::new("history")
+                .description("Show who did what to the light recently")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Integer,
+                        "count",
+                        "How many actions to show",
+                    )
+                    .min_int_value(1)
+                    .max_int_value(MAX_HISTORY_COUNT),
+                ),
+            CreateCommand::new("about")
+                .description("Show the device's model and firmware and the bot's version"),
+            CreateCommand::new("dnd")
+                .description("Stop the bot posting alerts and announcements on its own")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "state",
+                        "Do not disturb state",
+                    )
+                    .required(true)
+                    .add_string_choice("on", "on")
+                    .add_string_choice("off", "off"),
+                ),
+            CreateCommand::new("dimmer")
+                .description("Adjust the brightness with buttons that update as you press them"),
+            CreateCommand::new("cron-check")
+                .description("Check a cron expression and show when it would next fire")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "expression",
+                        "Cron expression with seconds, like 0 0 17 * * Mon-Fri",
+                    )
+                    .required(true),
+                ),
+            CreateCommand::new("drift")
+                .description("Show how late scheduled jobs have been firing"),
+            CreateCommand::new("uptime")
+                .description("Show how long the bot has been running and the last command used"),
+            CreateCommand::new("setup")
+                .description("Set up the light's address, credentials and schedule (owner only)"),
+            CreateCommand::new("skip-next")
+                .description("Skip the next run of a scheduled job")
+                .add_option(scheduled_job_option(
+                    "Job to skip",
+                    &schedules,
+                    &["on", "off"],
+                )),
+            CreateCommand::new("shift")
+                .description("Move the next run of a scheduled job earlier or later, just once")
+                .add_option(scheduled_job_option(
+                    "Job to move",
+                    &schedules,
+                    &["on", "off"],
+                ))
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "offset",
+                        "How far to move it, like +30m or -1h",
+                    )
+                    .required(true),
+                ),
+            CreateCommand::new("pause")
+                .description("Pause scheduled jobs, leaving the light as it is")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Integer,
+                        "minutes",
+                        "How long to pause for, or 0 to resume now",
+                    )
+                    .min_int_value(0)
+                    .max_int_value(MAX_PAUSE_MINUTES)
+                    .required(true),
+                ),
+            CreateCommand::new("at")
+                .description("Run an action once, the next time the clock reads the given time")
+                .add_option(
+                    CreateCommandOption::new(CommandOptionType::String, "time", "When, like 20:00")
+                        .required(true),
+                )
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "action",
+                        "on, off or brightness <percent>",
+                    )
+                    .required(true),
+                ),
+            CreateCommand::new("schedule")
+                .description("Show the light's schedule or turn a scheduled job on or off")
+                .add_option(CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "show",
+                    "Show when each scheduled job runs next",
+                ))
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::SubCommand,
+                        "disable",
+                        "Stop a scheduled job from running until it's enabled again",
+                    )
+                    .add_sub_option(scheduled_job_option(
+                        "Scheduled job",
+                        &schedules,
+                        &["off", "on", "led"],
+                    )),
+                )
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::SubCommand,
+                        "enable",
+                        "Let a disabled scheduled job run again",
+                    )
+                    .add_sub_option(scheduled_job_option(
+                        "Scheduled job",
+                        &schedules,
+                        &["off", "on", "led"],
+                    )),
+                ),
+            CreateCommand::new("wifi")
+                .description("Scan for or join Wi-Fi networks from the device (owner only)")
+                .add_option(CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "scan",
+                    "List the Wi-Fi networks the device can see",
+                ))
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::SubCommand,
+                        "join",
+                        "Move the device to another Wi-Fi network",
+                    )
+                    .add_sub_option(
+                        CreateCommandOption::new(CommandOptionType::String, "ssid", "Network name")
+                            .max_length(32)
+                            .required(true),
+                    )
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::String,
+                            "password",
+                            "Network password, if it has one",
+                        )
+                        .max_length(64),
+                    )
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::Integer,
+                            "security",
+                            "Network security, WPA2 if not set",
+                        )
+                        .add_int_choice("None", 0)
+                        .add_int_choice("WEP", 1)
+                        .add_int_choice("WPA", 2)
+                        .add_int_choice("WPA2", 3),
+                    ),
+                ),
+            CreateCommand::new("cloud")
+                .description("Turn the device's cloud connection on or off (owner only)")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "state",
+                        "Cloud connection state",
+                    )
+                    .required(true)
+                    .add_string_choice("on", "on")
+                    .add_string_choice("off", "off"),
+                ),
+        ]
+        .into_iter()
+        .chain(has_nightlight.then(|| {
+            CreateCommand::new("nightlight")
+                .description("Turn the device's nightlight on or off")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "state",
+                        "Nightlight state",
+                    )
+                    .required(true)
+                    .add_string_choice("on", "on")
+                    .add_string_choice("off", "off"),
+                )
+        }))
+        // Server admins can grant access to other roles or members through
+        // Server Settings > Integrations
+        .map(|command| command.default_member_permissions(Permissions::MANAGE_GUILD))
+        // Anyone can check their own permissions, so this one stays unrestricted
+        .chain(std::iter::once(
+            CreateCommand::new("whoami")
+                .description("Show what the bot thinks your permissions are"),
+        ))
+        .collect();
+
+        if let Err(why) = serenity::all::Command::set_global_commands(&ctx.http, commands).await {
+            error!("Failed to register slash commands: {:?}", why);
+        }
+    }
+
+    /// Sets up the control channel in each of `guild_ids`, which are the
+    /// guilds on the shard that just connected.
+    async fn setup_control_channel(
+        &self,
+        ctx: &Context,
+        guild_ids: &[GuildId],
+    ) -> Result<(), String> {
+        let mut failed_guilds = Vec::new();
+        let control_channel_name = self.control_channel_name();
+        for &guild_id in guild_ids {
+            // If we can't list the channels, skip this guild entirely rather than
+            // risk a duplicate panel.
+            let channels = match guild_id.channels(&ctx.http).await {
+                Ok(channels) => channels,
+                Err(e) => {
+                    error!(
+                        "Failed to list channels in guild {}, skipping control channel setup: {:?}",
+                        guild_id, e
+                    );
+                    failed_guilds.push(guild_id);
+                    continue;
+                }
+            };
+            let mut existing: Vec<ChannelId> = channels
+                .iter()
+                .filter(|(_, channel)| channel.name == control_channel_name)
+                .map(|(&channel_id, _)| channel_id)
+                .collect();
+            // Snowflakes sort by creation time, so the first is the oldest
+            existing.sort();
+
+            let channel_id = match existing.split_first() {
+                Some((&oldest, duplicates)) => {
+                    // Past failures can leave several control channels behind, so
+                    // consolidate on the oldest one
+                    for &duplicate in duplicates {
+                        match duplicate.delete(&ctx.http).await {
+                            Ok(_) => info!(
+                                "Deleted duplicate control channel {} in guild {}",
+                                duplicate, guild_id
+                            ),
+                            Err(e) => error!(
+                                "Failed to delete duplicate control channel {}: {:?}",
+                                duplicate, e
+                            ),
+                        }
+                    }
+                    self.delete_old_panels(ctx, oldest).await;
+                    oldest
+                }
+                None => match guild_id
+                    .create_channel(
+                        &ctx.http,
+                        CreateChannel::new(&control_channel_name).kind(ChannelType::Text),
+                    )
+                    .await
+                {
+                    Ok(channel) => channel.id,
+                    Err(why) => {
+                        error!("Error creating control channel: {:?}", why);
+                        self.control_channels.write().await.remove(&guild_id);
+                        failed_guilds.push(guild_id);
+                        continue;
+                    }
+                },
+            };
+
+            self.control_channels
+                .write()
+                .await
+                .insert(guild_id, channel_id);
+
+            // Create the control message with buttons
+            match self.send_panel(ctx, channel_id).await {
+                Ok(()) => {}
+                Err(why) if is_missing_permissions(&why) => {
+                    warn!(
+                        "The bot is missing the Send Messages permission in #{} ({}) in guild {}. \
+                         Grant it View Channel and Send Messages there; trying another channel for now.",
+                        control_channel_name, channel_id, guild_id
+                    );
+                    match self
+                        .send_panel_to_fallback(ctx, &channels, channel_id)
+                        .await
+                    {
+                        Some(fallback) => {
+                            warn!(
+                                "Posted the control panel in fallback channel {} in guild {}",
+                                fallback, guild_id
+                            );
+                            self.control_channels
+                                .write()
+                                .await
+                                .insert(guild_id, fallback);
+                        }
+                        None => {
+                            error!(
+                                "No channel in guild {} accepted the control panel",
+                                guild_id
+                            );
+                            failed_guilds.push(guild_id);
+                        }
+                    }
+                }
+                Err(why) => {
+                    error!("Error sending control message: {:?}", why);
+                    failed_guilds.push(guild_id);
+                }
+            }
+        }
+
+        if failed_guilds.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Control channel setup failed in guilds: {:?}",
+                failed_guilds
+            ))
+        }
+    }
+
+    async fn send_panel(&self, ctx: &Context, channel_id: ChannelId) -> serenity::Result<()> {
+        let message = channel_id
+            .send_message(
+                &ctx.http,
+                CreateMessage::new()
+                    .content(self.panel_content().await)
+                    .components(control_panel_components(
+                        &self.groups,
+                        self.brightness_select,
+                    )),
+            )
+            .await?;
+        self.panel_messages
+            .write()
+            .await
+            .insert(channel_id, message.id);
+        if self.reaction_controls {
+            if let Err(e) = self.send_reaction_controls(ctx, channel_id).await {
+                error!("Failed to set up reaction controls: {:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Posts and pins a message that switches the light when people react
+    /// to it, for clients where buttons are awkward.
+    async fn send_reaction_controls(
+        &self,
+        ctx: &Context,
+        channel_id: ChannelId,
+    ) -> serenity::Result<()> {
+        let message = channel_id
+            .say(
+                &ctx.http,
+                format!(
+                    "React with {} to turn the light on or {} to turn it off.",
+                    REACTION_ON, REACTION_OFF
+                ),
+            )
+            .await?;
+        self.reaction_messages.write().await.insert(message.id);
+        for emoji in [REACTION_ON, REACTION_OFF] {
+            message
+                .react(&ctx.http, ReactionType::Unicode(emoji.to_string()))
+                .await?;
+        }
+        message.pin(&ctx.http).await
+    }
+
+    /// Switches the light for a reaction on the reaction controls message,
+    /// then takes the reaction back off so it can be used again.
+    async fn handle_reaction(&self, ctx: &Context, reaction: Reaction) {
+        if !self
+            .reaction_messages
+            .read()
+            .await
+            .contains(&reaction.message_id)
+        {
+            return;
+        }
+        // The bot's own reactions set up the message
+        if reaction
+            .member
+            .as_ref()
+            .is_some_and(|member| member.user.bot)
+        {
+            return;
+        }
+        let (Some(custom_id), Some(user_id)) =
+            (reaction_control(&reaction.emoji), reaction.user_id)
+        else {
+            return;
+        };
+        if let Err(e) = reaction.delete(&ctx.http).await {
+            warn!("Failed to remove control reaction: {:?}", e);
+        }
+
+        let outcome = if !self.has_control_role(reaction.member.as_ref()) {
+            ComponentOutcome::Failed(self.missing_role_message())
+        } else if !self.rate_limiter.try_acquire() {
+            ComponentOutcome::Failed(RATE_LIMITED_MESSAGE.to_string())
+        } else {
+            *self.last_command.write().await = Some(LastCommand {
+                name: format!("reaction {}", reaction.emoji),
+                at: Utc::now(),
+            });
+            self.dispatch_component(custom_id)
+                .await
+                .expect("reaction controls map to panel buttons")
+        };
+        match &outcome {
+            ComponentOutcome::Done(reply) => {
+                self.usage_stats
+                    .record(user_id.get(), Utc::now().timestamp())
+                    .await;
+                *self.last_manual_action.write().await = Some(Instant::now());
+                self.record_history(format!("reaction {}", reaction.emoji), user_id, reply)
+                    .await;
+            }
+            // Reactions can't get a private reply, so failures go to the channel
+            ComponentOutcome::Queued(e) | ComponentOutcome::Failed(e) => {
+                self.record_history(format!("reaction {}", reaction.emoji), user_id, e)
+                    .await;
+                if self.do_not_disturb.load(Ordering::SeqCst) {
+                    info!("Do not disturb is on, not posting reaction failure: {}", e);
+                } else if let Err(why) = reaction
+                    .channel_id
+                    .say(&ctx.http, format!("<@{}> {}", user_id, e))
+                    .await
+                {
+                    error!("Cannot send reaction failure: {:?}", why);
+                }
+            }
+        }
+    }
+
+    /// The control panel's text, rendered from `PANEL_TEMPLATE` if set.
+    async fn panel_content(&self) -> String {
+        let Some(template) = &self.panel_template else {
+            return "Light Controls".to_string();
+        };
+        let state = match *self.light_state.read().await {
+            Some(true) => "on",
+            Some(false) => "off",
+            None => "unknown",
+        };
+        template
+            .replace("{device_name}", &self.device.name)
+            .replace("{state}", state)
+    }
+
+    /// Posts the control panel in the first other text channel that allows
+    /// it, returning that channel.
+    async fn send_panel_to_fallback(
+        &self,
+        ctx: &Context,
+        channels: &HashMap<ChannelId, GuildChannel>,
+        skip: ChannelId,
+    ) -> Option<ChannelId> {
+        let mut candidates: Vec<&GuildChannel> = channels
+            .values()
+            .filter(|channel| channel.kind == ChannelType::Text && channel.id != skip)
+            .collect();
+        candidates.sort_by_key(|channel| channel.position);
+
+        for channel in candidates.into_iter().take(MAX_FALLBACK_CHANNEL_ATTEMPTS) {
+            match self.send_panel(ctx, channel.id).await {
+                Ok(()) => return Some(channel.id),
+                Err(e) => info!("Can't post the control panel in #{}: {:?}", channel.name, e),
+            }
+        }
+        None
+    }
+
+    /// Deletes the bot's previous messages in a reused control channel so only
+    /// the fresh panel remains.
+    async fn delete_old_panels(&self, ctx: &Context, channel_id: ChannelId) {
+        let bot_id = match ctx.http.get_current_user().await {
+            Ok(user) => user.id,
+            Err(e) => {
+                error!("Failed to fetch current user: {:?}", e);
+                return;
+            }
+        };
+        let messages = match channel_id
+            .messages(&ctx.http, GetMessages::new().limit(50))
+            .await
+        {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("Failed to read control channel {}: {:?}", channel_id, e);
+                return;
+            }
+        };
+
+        for message in messages
+            .iter()
+            .filter(|message| message.author.id == bot_id)
+        {
+            if let Err(e) = message.delete(&ctx.http).await {
+                error!("Failed to delete old control message: {:?}", e);
+            }
+        }
+    }
+
+    /// Runs a kasa command, retrying transient failures. Once retries are
+    /// exhausted enough times in a row, the control channels are notified a
+    /// single time until a command succeeds again.
+    async fn execute_light_command(
+        &self,
+        device: &Device,
+        args: &[&str],
+    ) -> Result<String, String> {
+        let started = Instant::now();
+        let timeout = device.timeout.unwrap_or(self.kasa_timeout);
+        let mut attempt = 0;
+        let result = loop {
+            let result = tokio::time::timeout(timeout, self.controller.run(device, args))
+                .await
+                .unwrap_or_else(|_| Err(format!("Timed out after {}s", timeout.as_secs())));
+            match result {
+                // Unknown features won't appear by retrying
+                Err(e) if attempt < self.kasa_retries && !e.contains("No feature by name") => {
+                    attempt += 1;
+                    warn!(
+                        "Kasa command failed, retrying ({}/{}): {}",
+                        attempt, self.kasa_retries, e
+                    );
+                    tokio::time::sleep(KASA_RETRY_DELAY).await;
+                }
+                result => break result,
+            }
+        };
+
+        // Only the action, since later arguments can hold credentials
+        let action = args.iter().take(2).copied().collect::<Vec<_>>().join(" ");
+        let elapsed = started.elapsed();
+        debug!(
+            "Kasa command `{}` on {} took {:?}",
+            action, device.name, elapsed
+        );
+        if elapsed > self.slow_command_threshold {
+            warn!(
+                "Kasa command `{}` on {} was slow: took {:?}",
+                action, device.name, elapsed
+            );
+        }
+
+        match &result {
+            Ok(_) => {
+                self.consecutive_failures.store(0, Ordering::SeqCst);
+                if self.failure_notified.swap(false, Ordering::SeqCst) {
+                    info!("Kasa commands are succeeding again");
+                }
+            }
+            Err(e) if !e.contains("No feature by name") => {
+                let failures = self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+                if failures >= self.failure_notify_threshold
+                    && !self.failure_notified.swap(true, Ordering::SeqCst)
+                {
+                    self.notify_control_channels(&format!(
+                        "⚠️ Commands to {} at {} are failing after {} attempts: {}",
+                        device.name,
+                        device.host,
+                        attempt + 1,
+                        e.trim()
+                    ))
+                    .await;
+                }
+            }
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    /// Posts a message to every control channel. Does nothing before the bot
+    /// has connected.
+    async fn notify_control_channels(&self, content: &str) {
+        let channels: Vec<ChannelId> = self
+            .control_channels
+            .read()
+            .await
+            .values()
+            .copied()
+            .collect();
+        self.notify_channels(channels, content).await;
+    }
+
+    /// Posts a message to the control channels of just these guilds.
+    async fn notify_guilds(&self, guilds: &[GuildId], content: &str) {
+        let channels: Vec<ChannelId> = self
+            .control_channels
+            .read()
+            .await
+            .iter()
+            .filter(|(guild_id, _)| guilds.contains(guild_id))
+            .map(|(_, channel_id)| *channel_id)
+            .collect();
+        self.notify_channels(channels, content).await;
+    }
+
+    async fn notify_channels(&self, channels: Vec<ChannelId>, content: &str) {
+        if self.do_not_disturb.load(Ordering::SeqCst) {
+            info!("Do not disturb is on, not posting: {}", content);
+            return;
+        }
+        let Some(ctx) = self.discord_context() else {
+            warn!("Not connected yet, dropping notification: {}", content);
+            return;
+        };
+
+        for channel_id in channels {
+            if let Err(e) = channel_id.say(&ctx.http, content).await {
+                error!("Failed to send notification to control channel: {:?}", e);
+            }
+        }
+    }
+
+    async fn set_auto_off(&self, enabled: bool, minutes: Option<u32>) -> Result<(), String> {
+        // First set the minutes if provided
+        if let Some(mins) = minutes {
+            self.execute_light_command(
+                &self.device,
+                &["feature", "auto_off_minutes", &mins.to_string()],
+            )
+            .await?;
+        }
+
+        // Then enable/disable the feature
+        self.execute_light_command(
+            &self.device,
+            &[
+                "feature",
+                "auto_off_enabled",
+                if enabled { "True" } else { "False" },
+            ],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Turns the light on, restoring its brightness if a timer dimmed it.
+    /// Returns whether a remembered brightness was restored.
+    async fn turn_on(&self) -> Result<bool, String> {
+        self.execute_light_command(&self.device, &["on"]).await?;
+        let restore = self
+            .restore_brightness
+            .write()
+            .await
+            .remove(&self.device.host)
+            .filter(|remembered| {
+                remembered
+                    .expires
+                    .is_none_or(|expires| Instant::now() < expires)
+            });
+        let Some(remembered) = restore else {
+            return Ok(false);
+        };
+        match self.set_brightness(remembered.percent).await {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                error!("Failed to restore brightness: {}", e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Runs a kasa command with `--json` and parses its output.
+    async fn execute_light_json<T: serde::de::DeserializeOwned>(
+        &self,
+        args: &[&str],
+    ) -> Result<T, String> {
+        let args: Vec<&str> = std::iter::once("--json")
+            .chain(args.iter().copied())
+            .collect();
+        let stdout = self.execute_light_command(&self.device, &args).await?;
+        parse_json(&stdout)
+    }
+
+    async fn query_sysinfo(&self) -> Result<SysInfo, String> {
+        self.execute_light_json(&["sysinfo"]).await
+    }
+
+    async fn query_brightness(&self) -> Result<u8, String> {
+        self.query_sysinfo()
+            .await?
+            .brightness()
+            .ok_or_else(|| format!("{} doesn't report a brightness", self.device.name))
+    }
+
+    /// Describes the device's auto-off setting, e.g. `on after 30 minutes`.
+    async fn query_auto_off(&self) -> Result<String, String> {
+        let enabled = self
+            .execute_light_command(&self.device, &["feature", "auto_off_enabled"])
+            .await?;
+        if parse_feature_value(&enabled, "auto_off_enabled") != Some("True") {
+            return Ok("off".to_string());
+        }
+        let minutes = self
+            .execute_light_command(&self.device, &["feature", "auto_off_minutes"])
+            .await?;
+        match parse_feature_value(&minutes, "auto_off_minutes") {
+            Some(minutes) => Ok(format!(
+                "on, turning the light off {} minutes after it turns on",
+                minutes.split_whitespace().next().unwrap_or(minutes)
+            )),
+            None => Err(format!(
+                "Unexpected auto-off minutes output: {}",
+                minutes.trim()
+            )),
+        }
+    }
+
+    async fn turn_on_timed(&self, minutes: u32) -> Result<(), String> {
+        self.cancel_timer().await;
+        // First turn on the light
+        self.turn_on().await?;
+        // Then set up auto-off, which still turns the light off if the bot is
+        // down when the timer ends
+        self.set_auto_off(true, Some(minutes)).await
+    }
+
+    /// Turns the lights on for `minutes` and tracks the timers, spawning
+    /// tasks that dim the lights over the end of the timer and turn them off.
+    /// Returns the secondary light's error, if any, like `mirrored`.
+    async fn start_timer(&self, minutes: u32) -> Result<Option<String>, String> {
+        let secondary_error = self
+            .mirrored(|h| async move { h.turn_on_timed(minutes).await })
+            .await?;
+
+        self.track_timer(minutes, true).await;
+        if secondary_error.is_none() {
+            if let Some(secondary) = self.secondary() {
+                secondary.track_timer(minutes, false).await;
+            }
+        }
+        Ok(secondary_error)
+    }
+
+    /// Records a timer for this handler's light that ends in `minutes`.
+    /// `record_state` is set for the primary light, whose state is cached.
+    async fn track_timer(&self, minutes: u32, record_state: bool) {
+        let timer = Duration::from_secs(u64::from(minutes) * 60);
+        let dim_task = self.timer_dim.filter(|&dim| dim < timer).map(|dim| {
+            let handler = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(timer - dim).await;
+                info!(
+                    "Timer ending, dimming {} over {:?}",
+                    handler.device.name, dim
+                );
+                match handler.dim_and_turn_off(dim).await {
+                    Ok(()) if record_state => handler.record_light_state(false).await,
+                    Ok(()) => {}
+                    Err(e) => error!(
+                        "Failed to dim {} at end of timer: {}",
+                        handler.device.name, e
+                    ),
+                }
+            })
+        });
+
+        self.timers.lock().await.insert(
+            self.device.host.clone(),
+            ActiveTimer {
+                device_name: self.device.name.clone(),
+                ends_at: Utc::now() + chrono::Duration::minutes(minutes.into()),
+                dim_task,
+            },
+        );
+    }
+
+    /// Timers that haven't ended yet, as (device name, end time), soonest first.
+    async fn active_timers(&self) -> Vec<(String, DateTime<Utc>)> {
+        let now = Utc::now();
+        let mut timers: Vec<(String, DateTime<Utc>)> = self
+            .timers
+            .lock()
+            .await
+            .values()
+            .filter(|timer| timer.ends_at > now)
+            .map(|timer| (timer.device_name.clone(), timer.ends_at))
+            .collect();
+        timers.sort_by_key(|(_, ends_at)| *ends_at);
+        timers
+    }
+
+    /// Steps the light down to minimum brightness over `duration`, then turns
+    /// it off. Lights that can't be dimmed are left for the device's auto-off.
+    async fn dim_and_turn_off(&self, duration: Duration) -> Result<(), String> {
+        let start = self.query_brightness().await?;
+
+        let steps = self.timer_dim_steps;
+        let step_delay = duration / steps;
+        for step in 1..=steps {
+            let brightness = start - (u32::from(start).saturating_sub(1) * step / steps) as u8;
+            self.set_brightness(brightness.max(1)).await?;
+            tokio::time::sleep(step_delay).await;
+        }
+
+        self.execute_light_command(&self.device, &["off"]).await?;
+        self.restore_brightness.write().await.insert(
+            self.device.host.clone(),
+            RememberedBrightness {
+                percent: start,
+                expires: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Forgets this handler's light's timer and stops any dimming it would
+    /// do. The device's own auto-off is left alone.
+    async fn cancel_timer(&self) {
+        let timer = self.timers.lock().await.remove(&self.device.host);
+        if let Some(task) = timer.and_then(|timer| timer.dim_task) {
+            if !task.is_finished() {
+                info!("Cancelling timer dim for {}", self.device.name);
+            }
+            task.abort();
+        }
+    }
+
+    async fn turn_off(&self) -> Result<(), String> {
+        // Turning the light off ends any sunrise or timer in progress
+        self.cancel_sunrise().await;
+        self.cancel_timer().await;
+        let temp_restore_to = self.cancel_temp_brightness().await;
+        if let Some(window) = self.preserve_brightness {
+            // Plain plugs have no brightness, so there's nothing to remember.
+            // A temporary brightness isn't worth remembering either.
+            let brightness = match temp_restore_to {
+                Some(percent) => Ok(percent),
+                None => self.query_brightness().await,
+            };
+            if let Ok(brightness) = brightness {
+                self.restore_brightness.write().await.insert(
+                    self.device.host.clone(),
+                    RememberedBrightness {
+                        percent: brightness,
+                        expires: Some(Instant::now() + window),
+                    },
+                );
+            }
+        }
+        self.execute_light_command(&self.device, &["off"])
+            .await
+            .map(|_| ())
+    }
+
+    /// Sets the brightness for `duration`, then puts the previous one back.
+    async fn set_temp_brightness(&self, percent: u8, duration: Duration) -> Result<(), String> {
+        // Replacing a temporary brightness still reverts to the original
+        let restore_to = match self.cancel_temp_brightness().await {
+            Some(restore_to) => restore_to,
+            None => self.query_brightness().await?,
+        };
+        self.set_brightness(percent).await?;
+
+        let handler = self.clone();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            handler
+                .temp_brightness
+                .lock()
+                .await
+                .remove(&handler.device.host);
+            match handler.set_brightness(restore_to).await {
+                Ok(()) => info!(
+                    "Reverted {} to {}% brightness",
+                    handler.device.name, restore_to
+                ),
+                Err(e) => error!("Failed to revert temporary brightness: {}", e),
+            }
+        });
+        self.temp_brightness.lock().await.insert(
+            self.device.host.clone(),
+            TempBrightness { restore_to, task },
+        );
+        Ok(())
+    }
+
+    /// Stops a temporary brightness from reverting, returning the brightness
+    /// it would have reverted to.
+    async fn cancel_temp_brightness(&self) -> Option<u8> {
+        let temp = self
+            .temp_brightness
+            .lock()
+            .await
+            .remove(&self.device.host)?;
+        temp.task.abort();
+        Some(temp.restore_to)
+    }
+
+    /// Reads the light's current setting.
+    async fn take_snapshot(&self) -> Result<Snapshot, String> {
+        let sysinfo = self.query_sysinfo().await?;
+        Ok(Snapshot {
+            on: sysinfo
+                .is_on()
+                .ok_or_else(|| format!("{} doesn't report whether it's on", self.device.name))?,
+            brightness: sysinfo.brightness(),
+            color_temp: sysinfo.color_temp(),
+            hue_saturation: sysinfo.hue_saturation(),
+        })
+    }
+
+    /// Puts back a saved setting. Settings the light can't have, such as
+    /// a color on a dimmer, were never saved, so they're skipped.
+    async fn restore_snapshot(&self, snapshot: Snapshot) -> Result<(), String> {
+        if !snapshot.on {
+            return self.turn_off().await;
+        }
+        self.turn_on().await?;
+        if let Err(e) = self.arm_safety_cutoff().await {
+            error!("Failed to arm safety cutoff: {}", e);
+        }
+        if let Some(kelvin) = snapshot.color_temp {
+            self.set_color_temp(kelvin).await?;
+        } else if let Some((hue, saturation)) = snapshot.hue_saturation {
+            let value = self.quiet_hours_cap(snapshot.brightness.unwrap_or(100));
+            self.execute_light_command(
+                &self.device,
+                &[
+                    "hsv",
+                    &hue.to_string(),
+                    &saturation.to_string(),
+                    &value.to_string(),
+                ],
+            )
+            .await?;
+            // hsv sets the brightness too
+            return Ok(());
+        }
+        if let Some(percent) = snapshot.brightness {
+            self.set_brightness(percent).await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets any remembered brightness and, on dimmers, sets the
+    /// brightness the light comes on at next.
+    async fn reset_brightness(&self, percent: u8) -> Result<(), String> {
+        self.restore_brightness
+            .write()
+            .await
+            .remove(&self.device.host);
+        // Setting a bulb's brightness turns it on, so only dimmers can be
+        // changed while off
+        if self.device.kind != DeviceKind::Dimmer {
+            return Ok(());
+        }
+        self.set_brightness(percent).await
+    }
+
+    async fn set_color_temp(&self, kelvin: u16) -> Result<(), String> {
+        self.execute_light_command(
+            &self.device,
+            &["feature", "color_temperature", &kelvin.to_string()],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Warms a bulb to `kelvin` if it's on, showing a white, and cooler than
+    /// that. It's never made cooler, so a warmer choice made by hand sticks.
+    async fn warm_color_temp(&self, kelvin: u16) -> Result<(), String> {
+        if self.device.kind != DeviceKind::Bulb {
+            return Ok(());
+        }
+        let sysinfo = self.query_sysinfo().await?;
+        if sysinfo.is_on() != Some(true) {
+            return Ok(());
+        }
+        match sysinfo.color_temp() {
+            Some(current) if current > kelvin => {
+                info!(
+                    "Warming {} from {}K to {}K",
+                    self.device.name, current, kelvin
+                );
+                self.set_color_temp(kelvin).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Moves bulbs along `COLOR_TEMP_CURVE` while they're on in the evening.
+    async fn run_color_temp_job(&self) {
+        let Some(curve) = &self.color_temp_curve else {
+            return;
+        };
+        let now = Utc::now().with_timezone(&self.timezone);
+        let Some(kelvin) = curve.at(now.time()) else {
+            return;
+        };
+        if self.pause_remaining().await.is_some() || self.blackout_until(&now).is_some() {
+            return;
+        }
+        match self
+            .mirrored(|h| async move { h.warm_color_temp(kelvin).await })
+            .await
+        {
+            Ok(None) => {}
+            Ok(Some(e)) => error!("Failed to warm secondary light: {}", e),
+            Err(e) => error!("Failed to warm light: {}", e),
+        }
+    }
+
+    /// Caps `percent` at `QUIET_MAX_BRIGHTNESS` during quiet hours.
+    fn quiet_hours_cap(&self, percent: u8) -> u8 {
+        match self.quiet_hours {
+            Some((hours, max))
+                if hours.contains(Utc::now().with_timezone(&self.timezone).time()) =>
+            {
+                percent.min(max)
+            }
+            _ => percent,
+        }
+    }
+
+    /// Tells whoever asked for `percent` that quiet hours capped it, if they did.
+    fn quiet_hours_note(&self, percent: u8) -> String {
+        match self.quiet_hours_cap(percent) {
+            capped if capped < percent => {
+                format!(" Quiet hours capped the brightness at {}%.", capped)
+            }
+            _ => String::new(),
+        }
+    }
+
+    async fn set_brightness(&self, percent: u8) -> Result<(), String> {
+        let capped = self.quiet_hours_cap(percent);
+        if capped < percent {
+            info!(
+                "Capping brightness {}% at {}% for quiet hours",
+                percent, capped
+            );
+        }
+        let percent = capped;
+        self.execute_light_command(
+            &self.device,
+            &["feature", "brightness", &percent.to_string()],
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Turns the light on at minimum brightness, then spawns a task that ramps
+    /// it up to full brightness over the configured sunrise duration. Returns
+    /// the secondary light's error, if any, like `mirrored`.
+    async fn start_sunrise(&self) -> Result<Option<String>, String> {
+        self.cancel_sunrise().await;
+        let secondary_error = self
+            .mirrored(|h| async move {
+                h.cancel_timer().await;
+                h.set_brightness(1).await?;
+                h.execute_light_command(&h.device, &["on"]).await?;
+                if let Err(e) = h.arm_safety_cutoff().await {
+                    error!("Failed to arm safety cutoff: {}", e);
+                }
+                Ok(())
+            })
+            .await?;
+
+        let handler = self.clone();
+        let steps = self.sunrise_steps;
+        let step_delay = self.sunrise_duration / steps;
+        info!(
+            "Starting sunrise over {:?} in {} steps",
+            self.sunrise_duration, steps
+        );
+        let task = tokio::spawn(async move {
+            for step in 1..=steps {
+                tokio::time::sleep(step_delay).await;
+                let brightness = 1 + (99 * step / steps) as u8;
+                match handler
+                    .mirrored(|h| async move { h.set_brightness(brightness).await })
+                    .await
+                {
+                    Ok(None) => {}
+                    Ok(Some(e)) => error!("Failed to step secondary sunrise brightness: {}", e),
+                    Err(e) => error!("Failed to step sunrise brightness: {}", e),
+                }
+            }
+            info!("Sunrise complete");
+        });
+
+        *self.sunrise_task.lock().await = Some(task);
+        Ok(secondary_error)
+    }
+
+    async fn cancel_sunrise(&self) {
+        if let Some(task) = self.sunrise_task.lock().await.take() {
+            if !task.is_finished() {
+                info!("Cancelling sunrise in progress");
+            }
+            task.abort();
+        }
+    }
+
+    async fn turn_on_regular(&self) -> Result<(), String> {
+        // Turn on the light and disable auto-off, apart from the safety cutoff
+        self.cancel_timer().await;
+        // A remembered brightness wins over the curve
+        if !self.turn_on().await? {
+            self.apply_brightness_curve(self.timezone).await;
+        }
+        match self.max_on_minutes {
+            Some(_) => self.arm_safety_cutoff().await,
+            None => self.set_auto_off(false, None).await,
+        }
+    }
+
+    /// Sets the device's auto-off to `MAX_ON_MINUTES`, so a light turned on
+    /// without a timer doesn't stay on indefinitely. The device keeps track
+    /// of how long it's been on, even while the bot is down.
+    async fn arm_safety_cutoff(&self) -> Result<(), String> {
+        match self.max_on_minutes {
+            Some(minutes) => self.set_auto_off(true, Some(minutes)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the brightness the configured curve gives for the current hour
+    /// in `tz`. Failures are only logged, since plain plugs can't dim.
+    async fn apply_brightness_curve(&self, tz: Tz) {
+        let Some(curve) = &self.brightness_curve else {
+            return;
+        };
+        let percent = curve.at(Utc::now().with_timezone(&tz).hour());
+        if let Err(e) = self.set_brightness(percent).await {
+            error!("Failed to apply brightness curve: {}", e);
+        }
+    }
+
+    async fn query_cloud_connection(&self) -> Result<bool, String> {
+        let stdout = self
+            .execute_light_command(&self.device, &["feature", "cloud_connection"])
+            .await?;
+        match parse_feature_value(&stdout, "cloud_connection") {
+            Some("True") => Ok(true),
+            Some("False") => Ok(false),
+            _ => Err(format!(
+                "Unexpected cloud connection output: {}",
+                stdout.trim()
+            )),
+        }
+    }
+
+    /// Summarizes the power and temperature readings for devices that report
+    /// them, formatted in the configured units.
+    async fn readings_summary(&self) -> Option<String> {
+        let stdout = match self.execute_light_command(&self.device, &["feature"]).await {
+            Ok(stdout) => stdout,
+            Err(e) => {
+                error!("Failed to read device features: {}", e);
+                return None;
+            }
+        };
+        // Plugs with energy monitoring report power through the emeter
+        let emeter_watts = match self.query_sysinfo().await {
+            Ok(sysinfo) if sysinfo.has_emeter() => self
+                .execute_light_json::<EmeterStatus>(&["emeter"])
+                .await
+                .map_err(|e| error!("Failed to read emeter: {}", e))
+                .ok()
+                .and_then(|status| status.watts()),
+            _ => None,
+        };
+        let reading = |name: &str| -> Option<f64> {
+            parse_feature_value(&stdout, name)?
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok()
+        };
+
+        let mut lines = Vec::new();
+        if let Some(watts) = emeter_watts.or_else(|| reading("current_consumption")) {
+            lines.push(format!("Power: {}", self.units.format_power(watts)));
+        }
+        if let Some(kwh) = reading("consumption_today") {
+            lines.push(format!("Today: {}", self.units.format_energy(kwh)));
+        }
+        if let Some(celsius) = reading("temperature") {
+            lines.push(format!(
+                "Temperature: {}",
+                self.units.format_temperature(celsius)
+            ));
+        }
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    /// Reads when the light was turned on, or `None` if it's off.
+    async fn query_on_since(&self) -> Result<Option<DateTime<Utc>>, String> {
+        let stdout = self
+            .execute_light_command(&self.device, &["feature", "on_since"])
+            .await
+            .map_err(|e| {
+                if e.contains("No feature by name") {
+                    NO_ON_TIME_FEATURE_ERROR.to_string()
+                } else {
+                    e
+                }
+            })?;
+        match parse_feature_value(&stdout, "on_since") {
+            Some("None") => Ok(None),
+            Some(value) => parse_device_datetime(value, self.timezone)
+                .map(Some)
+                .ok_or_else(|| format!("Unexpected on_since value: {}", value)),
+            None => Err(format!("Unexpected on_since output: {}", stdout.trim())),
+        }
+    }
+
+    /// Binds or unbinds the device from the TP-Link cloud, returning the
+    /// connection state read back from the device.
+    async fn set_cloud_connection(&self, enabled: bool) -> Result<bool, String> {
+        if enabled {
+            let credentials = self.device.credentials();
+            let credentials = serde_json::json!({
+                "username": credentials.username,
+                "password": credentials.password,
+            })
+            .to_string();
+            self.execute_light_command(
+                &self.device,
+                &["command", "--module", "cnCloud", "bind", &credentials],
+            )
+            .await?;
+        } else {
+            self.execute_light_command(&self.device, &["command", "--module", "cnCloud", "unbind"])
+                .await?;
+        }
+        self.query_cloud_connection().await
+    }
+
+    /// The Wi-Fi networks the device can see, as kasa lists them.
+    async fn scan_wifi(&self) -> Result<String, String> {
+        let stdout = self
+            .execute_light_command(&self.device, &["wifi", "scan"])
+            .await?;
+        Ok(stdout.trim().to_string())
+    }
+
+    /// Tells the device to join another network. It drops off the current
+    /// one straight away, so kasa may time out even when this worked.
+    async fn join_wifi(&self, join: &WifiJoin) -> Result<String, String> {
+        let key_type = join.key_type.to_string();
+        let stdout = self
+            .execute_light_command(
+                &self.device,
+                &[
+                    "wifi",
+                    "join",
+                    &join.ssid,
+                    "--password",
+                    &join.password,
+                    "--keytype",
+                    &key_type,
+                ],
+            )
+            .await?;
+        Ok(stdout.trim().to_string())
+    }
+
+    /// Returns a copy of this handler that targets the secondary light, if one
+    /// is configured.
+    fn secondary(&self) -> Option<Handler> {
+        Some(self.for_device(self.secondary_device.clone()?))
+    }
+
+    /// Returns a copy of this handler that targets only `device`.
+    fn for_device(&self, device: Device) -> Handler {
+        Self {
+            device,
+            secondary_device: None,
+            ..self.clone()
+        }
+    }
+
+    /// The group a group button switches, and whether it switches it on.
+    fn group_button(&self, custom_id: &str) -> Option<(&DeviceGroup, bool)> {
+        let (on, index) = match custom_id.strip_prefix("group_on_") {
+            Some(index) => (true, index),
+            None => (false, custom_id.strip_prefix("group_off_")?),
+        };
+        Some((self.groups.get(index.parse::<usize>().ok()?)?, on))
+    }
+
+    /// Switches every light in `group` at once, so an offline light doesn't
+    /// hold up the others, and returns each light's outcome.
+    async fn switch_group(&self, group: &DeviceGroup, on: bool) -> Vec<Result<(), String>> {
+        let results = join_all(group.devices.iter().map(|device| {
+            let handler = self.for_device(device.clone());
+            async move {
+                if on {
+                    handler.turn_on_regular().await
+                } else {
+                    handler.turn_off().await
+                }
+            }
+        }))
+        .await;
+
+        for (device, result) in group.devices.iter().zip(&results) {
+            match result {
+                Ok(()) if device.host == self.device.host => self.record_light_state(on).await,
+                Ok(()) => {}
+                Err(e) => error!(
+                    "Failed to switch {} in group {}: {}",
+                    device.name, group.name, e
+                ),
+            }
+        }
+        results
+    }
+
+    /// Sums up a group switch, failing if any light failed.
+    fn group_summary(
+        &self,
+        group: &DeviceGroup,
+        on: bool,
+        results: &[Result<(), String>],
+    ) -> Result<String, String> {
+        let failed: Vec<&str> = group
+            .devices
+            .iter()
+            .zip(results)
+            .filter(|(_, result)| result.is_err())
+            .map(|(device, _)| device.name.as_str())
+            .collect();
+        let state = if on { "on" } else { "off" };
+        if failed.is_empty() {
+            let message = format!("{} turned {}!", group.name, state);
+            Ok(if on {
+                self.with_safety_cutoff(message)
+            } else {
+                message
+            })
+        } else if failed.len() == group.devices.len() {
+            Err(format!("Failed to turn {} {}", group.name, state))
+        } else {
+            Err(format!(
+                "Turned {} {}, except {} of {} lights failed: {}",
+                group.name,
+                state,
+                failed.len(),
+                group.devices.len(),
+                failed.join(", ")
+            ))
+        }
+    }
+
+    /// Runs `action` against the primary light and, if that succeeds, mirrors it
+    /// to the secondary light. The secondary's error is returned separately so
+    /// callers can report a partial success.
+    async fn mirrored<F, Fut>(&self, action: F) -> Result<Option<String>, String>
+    where
+        F: Fn(Handler) -> Fut,
+        Fut: Future<Output = Result<(), String>>,
+    {
+        action(self.clone()).await?;
+        match self.secondary() {
+            Some(secondary) => Ok(action(secondary).await.err()),
+            None => Ok(None),
+        }
+    }
+
+    /// Flips the light to the opposite of its current state, returning the new
+    /// state and the secondary light's error, if any.
+    async fn toggle_light(&self) -> Result<(bool, Option<String>), String> {
+        let on = !self.query_light_state().await?;
+        let secondary_error = if on {
+            self.mirrored(|h| async move { h.turn_on_regular().await })
+                .await?
+        } else {
+            self.mirrored(|h| async move { h.turn_off().await }).await?
+        };
+        Ok((on, secondary_error))
+    }
+
+    /// Flashes the light on and off `count` times, then restores its original state.
+    async fn blink(&self, count: usize) -> Result<(), String> {
+        let was_on = self.query_light_state().await?;
+        info!("Flashing light {} times", count);
+
+        for _ in 0..count {
+            self.execute_light_command(&self.device, &["on"]).await?;
+            tokio::time::sleep(LOCATE_FLASH_DELAY).await;
+            self.execute_light_command(&self.device, &["off"]).await?;
+            tokio::time::sleep(LOCATE_FLASH_DELAY).await;
+        }
+
+        if was_on {
+            self.execute_light_command(&self.device, &["on"]).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_led(&self, enabled: bool) -> Result<(), String> {
+        if !self.device.kind.has_led() {
+            return Err(NO_LED_FEATURE_ERROR.to_string());
+        }
+        self.execute_light_command(
+            &self.device,
+            &["feature", "led", if enabled { "True" } else { "False" }],
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            if e.contains("No feature by name") {
+                NO_LED_FEATURE_ERROR.to_string()
+            } else {
+                e
+            }
+        })
+    }
+
+    async fn set_nightlight(&self, enabled: bool) -> Result<(), String> {
+        self.execute_light_command(
+            &self.device,
+            &[
+                "feature",
+                "night_mode",
+                if enabled { "True" } else { "False" },
+            ],
+        )
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            if e.contains("No feature by name") {
+                NO_NIGHTLIGHT_FEATURE_ERROR.to_string()
+            } else {
+                e
+            }
+        })
+    }
+
+    async fn query_light_state(&self) -> Result<bool, String> {
+        self.query_sysinfo()
+            .await?
+            .is_on()
+            .ok_or_else(|| format!("{} doesn't report whether it's on", self.device.name))
+    }
+
+    /// Posts the light's state to the state webhook in the background, if one
+    /// is configured.
+    fn push_state(&self, on: bool) {
+        let Some(webhook) = self.state_webhook.clone() else {
+            return;
+        };
+        let handler = self.clone();
+        tokio::spawn(async move {
+            // Plain plugs have no brightness
+            let brightness = if on {
+                handler.query_brightness().await.ok()
+            } else {
+                None
+            };
+            let payload = serde_json::json!({
+                "device": handler.device.name,
+                "state": if on { "on" } else { "off" },
+                "brightness": brightness,
+                "timestamp": Utc::now().to_rfc3339(),
+            });
+            webhook.post(&payload).await;
+        });
+    }
+
+    /// Fires the webhook for `action` in the background, if one is configured.
+    fn fire_action_webhook(&self, action: Action) {
+        let (name, brightness) = match action {
+            Action::On => ("on", None),
+            Action::Off => ("off", None),
+            Action::Brightness(percent) => ("brightness", Some(self.quiet_hours_cap(percent))),
+        };
+        let Some(webhook) = self.action_webhooks.get(name).cloned() else {
+            return;
+        };
+        let device = self.device.name.clone();
+        let brightness =
+            brightness.map_or_else(|| "null".to_string(), |percent| percent.to_string());
+        tokio::spawn(async move {
+            webhook
+                .fire(&[
+                    ("device", &device),
+                    ("action", name),
+                    ("brightness", &brightness),
+                    ("timestamp", &Utc::now().to_rfc3339()),
+                ])
+                .await;
+        });
+    }
+
+    /// Records that the light was switched, firing its action webhook.
+    async fn record_light_state(&self, on: bool) {
+        self.fire_action_webhook(if on { Action::On } else { Action::Off });
+        self.refresh_light_state(on).await;
+    }
+
+    /// Reads the light's actual state when a shard connects, so the presence,
+    /// topics and panel are right before anyone touches the light. An
+    /// unreachable light is marked unknown rather than guessed.
+    async fn sync_light_state_on_ready(&self, ctx: &Context) {
+        let on = match self.query_light_state().await {
+            Ok(on) => on,
+            Err(e) => {
+                warn!(
+                    "Couldn't read the light's state on connect, marking it unknown: {}",
+                    e
+                );
+                // Close any on-time stretch, since it can't be vouched for
+                self.on_time.record(false);
+                *self.light_state.write().await = None;
+                ctx.set_activity(Some(ActivityData::custom("Light state unknown")));
+                self.show_light_state(ctx, "unknown").await;
+                return;
+            }
+        };
+        info!("The light is {} on connect", if on { "on" } else { "off" });
+        self.refresh_light_state(on).await;
+        // Each shard has its own presence, which the refresh only sets on one
+        ctx.set_activity(Some(ActivityData::custom(format!(
+            "Light is {}",
+            if on { "on" } else { "off" }
+        ))));
+    }
+
+    /// Records the light's state, refreshing the presence and control channel
+    /// topics if it changed.
+    async fn refresh_light_state(&self, on: bool) {
+        self.push_state(on);
+        self.on_time.record(on);
+        let previous = self.light_state.write().await.replace(on);
+        if previous == Some(on) {
+            return;
+        }
+        let Some(ctx) = self.discord_context() else {
+            return;
+        };
+
+        let description = if on { "on" } else { "off" };
+        ctx.set_activity(Some(ActivityData::custom(format!(
+            "Light is {}",
+            description
+        ))));
+
+        self.show_light_state(&ctx, description).await;
+    }
+
+    /// Shows the light's state in the control channel topics and any
+    /// templated control panel.
+    async fn show_light_state(&self, ctx: &Context, description: &str) {
+        let channels: Vec<ChannelId> = self
+            .control_channels
+            .read()
+            .await
+            .values()
+            .copied()
+            .collect();
+        for channel_id in channels {
+            if let Err(e) = channel_id
+                .edit(
+                    &ctx.http,
+                    EditChannel::new().topic(format!("The light is currently {}", description)),
+                )
+                .await
+            {
+                error!("Failed to update control channel topic: {:?}", e);
+            }
+        }
+
+        // Only a templated panel can show the state
+        if self.panel_template.is_some() {
+            let content = self.panel_content().await;
+            let panels: Vec<(ChannelId, MessageId)> = self
+                .panel_messages
+                .read()
+                .await
+                .iter()
+                .map(|(&channel_id, &message_id)| (channel_id, message_id))
+                .collect();
+            for (channel_id, message_id) in panels {
+                if let Err(e) = channel_id
+                    .edit_message(
+                        &ctx.http,
+                        message_id,
+                        EditMessage::new().content(content.clone()),
+                    )
+                    .await
+                {
+                    error!("Failed to update control panel: {:?}", e);
+                }
+            }
+        }
+    }
+
+    async fn run_reconcile_job(&self) {
+        let actual = match self.query_light_state().await {
+            Ok(actual) => actual,
+            Err(e) => {
+                error!("Failed to query light state for reconciliation: {}", e);
+                return;
+            }
+        };
+
+        // The light is back, so catch up on anything pressed while it was away
+        if self.drain_offline_queue().await {
+            return;
+        }
+
+        let cached = *self.light_state.read().await;
+        if let Some(cached) = cached.filter(|&cached| cached != actual) {
+            info!(
+                "Light state drift detected: cached {} but device reports {}",
+                cached, actual
+            );
+        }
+        self.refresh_light_state(actual).await;
+    }
+
+    /// Turns the light off if the bot started after the midnight-off job
+    /// should have run, e.g. after a crash.
+    async fn reconcile_schedule_on_start(&self) {
+        if self.job_disabled("off") {
+            return;
+        }
+        // Every guild shares the light, so only turn it off if none of their
+        // schedules would have it on
+        let now = Utc::now();
+        for (tz, _) in self.schedule_timezones().await {
+            if !self.in_scheduled_off_window(now.with_timezone(&tz)) {
+                return;
+            }
+            if self.blackout_until(&now.with_timezone(&tz)).is_some() {
+                info!("Started inside a blackout window, leaving the light as it is");
+                return;
+            }
+        }
+
+        info!(
+            "Started at {} inside the scheduled off window, turning the light off",
+            now
+        );
+        match self.turn_off().await {
+            Ok(_) => self.record_light_state(false).await,
+            Err(e) => error!("Failed to turn off light on startup: {}", e),
+        }
+    }
+
+    async fn run_midnight_off_job(&self, tz: Tz) -> Result<(), String> {
+        let now = Utc::now().with_timezone(&tz);
+        info!("Running off job ({}) at {}", self.schedules().off, now);
+        let result = match self.mirrored(|h| async move { h.turn_off().await }).await {
+            Ok(secondary_error) => {
+                self.record_light_state(false).await;
+                info!(
+                    "{}",
+                    with_secondary_result(
+                        "Successfully turned off light for the off job".to_string(),
+                        secondary_error
+                    )
+                );
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to execute scheduled light off command: {}", e);
+                Err(e)
+            }
+        };
+
+        if let Some(percent) = self.midnight_brightness {
+            match self
+                .mirrored(|h| async move { h.reset_brightness(percent).await })
+                .await
+            {
+                Ok(None) => info!("Reset brightness to {}% for the off job", percent),
+                Ok(Some(e)) => error!(
+                    "Failed to reset secondary brightness for the off job: {}",
+                    e
+                ),
+                Err(e) => error!("Failed to reset brightness for the off job: {}", e),
+            }
+        }
+
+        if self.midnight_nightlight {
+            match self.set_nightlight(true).await {
+                Ok(()) => info!("Turned the nightlight on for the off job"),
+                Err(e) => error!("Failed to turn on nightlight for the off job: {}", e),
+            }
+        }
+
+        // The status LED is cosmetic, so don't fail the job over it
+        match self
+            .mirrored(|h| async move { h.set_led(false).await })
+            .await
+        {
+            Ok(None) => {}
+            Ok(Some(e)) => error!("Failed to turn off secondary LED for the off job: {}", e),
+            Err(e) => error!("Failed to turn off LED for the off job: {}", e),
+        }
+
+        result
+    }
+
+    async fn run_morning_led_job(&self, tz: Tz) -> Result<(), String> {
+        let now = Utc::now().with_timezone(&tz);
+        info!("Running led job ({}) at {}", self.schedules().led_on, now);
+        match self
+            .mirrored(|h| async move { h.set_led(true).await })
+            .await
+        {
+            Ok(secondary_error) => {
+                info!(
+                    "{}",
+                    with_secondary_result(
+                        "Successfully turned on LED for the led job".to_string(),
+                        secondary_error
+                    )
+                );
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to turn on LED for the led job: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn run_evening_on_job(&self, tz: Tz) -> Result<(), String> {
+        let now = Utc::now().with_timezone(&tz);
+        info!("Running on job ({}) at {}", self.schedules().on, now);
+        match self
+            .mirrored(|h| async move {
+                let restored = h.turn_on().await?;
+                if let Err(e) = h.arm_safety_cutoff().await {
+                    error!("Failed to arm safety cutoff: {}", e);
+                }
+                // A remembered brightness wins over the configured ones
+                if restored {
+                    return Ok(());
+                }
+                match h.schedule_on_brightness {
+                    Some(percent) => {
+                        if let Err(e) = h.set_brightness(percent).await {
+                            error!("Failed to set scheduled on brightness: {}", e);
+                        }
+                    }
+                    None => h.apply_brightness_curve(tz).await,
+                }
+                Ok(())
+            })
+            .await
+        {
+            Ok(secondary_error) => {
+                self.record_light_state(true).await;
+                info!(
+                    "{}",
+                    with_secondary_result(
+                        "Successfully turned on light for the on job".to_string(),
+                        secondary_error
+                    )
+                );
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to execute scheduled light on command: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs each action from `SCHEDULED_ACTIONS` that's due now in `tz`.
+    async fn run_scheduled_actions(&self, tz: Tz) {
+        let now = Utc::now().with_timezone(&tz);
+        let due: Vec<Action> = self
+            .schedules()
+            .actions
+            .iter()
+            .filter(|entry| entry.schedule.includes(now))
+            .map(|entry| entry.action)
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+        if self.pause_remaining().await.is_some() {
+            info!(
+                "Automation is paused, not running scheduled actions in {}",
+                tz
+            );
+            return;
+        }
+        if let Some(until) = self.blackout_until(&now) {
+            info!(
+                "Blackout window until {}, not running scheduled actions in {}",
+                until, tz
+            );
+            return;
+        }
+
+        if self.yield_to_manual("scheduled actions").await {
+            return;
+        }
+        for action in due {
+            info!("Running scheduled action {} in {}", action, tz);
+            if let Err(e) = self.apply_action(action).await {
+                error!("Scheduled action {} failed: {}", action, e);
+            }
+        }
+    }
+
+    /// Applies an action to both lights, recording any state change. A
+    /// failure on the secondary light is only logged.
+    async fn apply_action(&self, action: Action) -> Result<(), String> {
+        let (state, secondary_error) = match action {
+            Action::On => (
+                Some(true),
+                self.mirrored(|h| async move { h.turn_on_regular().await })
+                    .await?,
+            ),
+            Action::Off => (
+                Some(false),
+                self.mirrored(|h| async move { h.turn_off().await }).await?,
+            ),
+            Action::Brightness(percent) => (
+                None,
+                self.mirrored(|h| async move { h.set_brightness(percent).await })
+                    .await?,
+            ),
+        };
+        match state {
+            Some(on) => self.record_light_state(on).await,
+            None => self.fire_action_webhook(action),
+        }
+        if let Some(e) = secondary_error {
+            error!("Action {} failed on the secondary light: {}", action, e);
+        }
+        Ok(())
+    }
+
+    fn queued_message(&self, action: &str) -> String {
+        let minutes = self.offline_queue_ttl.map_or(0, |ttl| ttl.as_secs() / 60);
+        format!(
+            "The light isn't reachable right now. It'll {} when it's back, if that's within {} minutes.",
+            action, minutes
+        )
+    }
+
+    /// Queues an action to apply once the light is reachable again, returning
+    /// false if the offline queue is disabled.
+    async fn queue_action(&self, action: Action) -> bool {
+        if self.offline_queue_ttl.is_none() {
+            return false;
+        }
+        info!("Queueing {} until the light is reachable", action);
+        self.offline_queue
+            .lock()
+            .await
+            .push((action, Instant::now()));
+        true
+    }
+
+    /// Applies the queued actions that haven't expired, in order. Returns
+    /// whether there were any.
+    async fn drain_offline_queue(&self) -> bool {
+        let Some(ttl) = self.offline_queue_ttl else {
+            return false;
+        };
+        let queued = std::mem::take(&mut *self.offline_queue.lock().await);
+        if queued.is_empty() {
+            return false;
+        }
+        for (action, queued_at) in queued {
+            if queued_at.elapsed() > ttl {
+                info!("Dropping queued {} after {:?}", action, queued_at.elapsed());
+                self.notify_control_channels(&format!(
+                    "The light is reachable again, but the queued {} action was too old to apply.",
+                    action
+                ))
+                .await;
+                continue;
+            }
+            info!("Applying queued {} now that the light is reachable", action);
+            if let Err(e) = self.apply_action(action).await {
+                error!("Queued action {} failed: {}", action, e);
+            }
+        }
+        true
+    }
+
+    /// Whether no member with the control role is online in any control
+    /// guild. Errs towards `false` so the light still turns on when unsure.
+    async fn everyone_away(&self) -> bool {
+        let Some(role_id) = self.control_role_id else {
+            warn!("AWAY_DETECTION needs CONTROL_ROLE_ID to be set, ignoring it");
+            return false;
+        };
+        let Some(ctx) = self.discord_context() else {
+            return false;
+        };
+
+        let guilds: Vec<GuildId> = self.control_channels.read().await.keys().copied().collect();
+        for guild_id in guilds {
+            // No presence data for the guild yet
+            let Some(online) = self.online_users.read().await.get(&guild_id).cloned() else {
+                return false;
+            };
+            if online.is_empty() {
+                continue;
+            }
+
+            let mut after = None;
+            loop {
+                let members = match guild_id.members(&ctx.http, Some(1000), after).await {
+                    Ok(members) => members,
+                    Err(e) => {
+                        error!("Failed to list members of guild {}: {:?}", guild_id, e);
+                        return false;
+                    }
+                };
+                if members.iter().any(|member| {
+                    member.roles.contains(&role_id) && online.contains(&member.user.id)
+                }) {
+                    return false;
+                }
+                match members.last() {
+                    Some(last) if members.len() == 1000 => after = Some(last.user.id),
+                    _ => break,
+                }
+            }
+        }
+        true
+    }
+
+    /// Whether the off job ran more recently than the on job.
+    fn in_scheduled_off_window(&self, now: DateTime<Tz>) -> bool {
+        let schedules = self.schedules();
+        match (schedules.off.previous(&now), schedules.on.previous(&now)) {
+            (Some(off), Some(on)) => off > on,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    fn job_disabled(&self, job: &str) -> bool {
+        self.disabled_jobs.read().unwrap().contains(job)
+    }
+
+    /// Turns a scheduled job on or off, saving the choice so it survives a
+    /// restart.
+    fn set_job_enabled(&self, job: &str, enabled: bool) -> Result<(), String> {
+        let disabled = {
+            let mut disabled_jobs = self.disabled_jobs.write().unwrap();
+            if enabled {
+                disabled_jobs.remove(job);
+            } else {
+                disabled_jobs.insert(job.to_string());
+            }
+            let mut disabled: Vec<&str> = disabled_jobs.iter().map(String::as_str).collect();
+            disabled.sort_unstable();
+            disabled.join(",")
+        };
+        save_env_vars(&[("DISABLED_JOBS", &disabled)])
+    }
+
+    /// The current schedules.
+    fn schedules(&self) -> Schedules {
+        self.schedules.read().unwrap().clone()
+    }
+
+    /// Re-reads the env file and switches to any changed schedules. The jobs
+    /// look up their schedule every tick, so they pick up changes right away.
+    async fn reload_schedules(&self) {
+        if let Err(e) = reload_env_file() {
+            error!("Failed to reload config: {}", e);
+            return;
+        }
+        let reloaded = match Schedules::from_config(ConfigSource::env()) {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                error!("Keeping the current schedules: {}", e);
+                return;
+            }
+        };
+
+        let previous = std::mem::replace(&mut *self.schedules.write().unwrap(), reloaded.clone());
+        let mut changed = false;
+        for ((job, old), (_, new)) in previous.jobs().into_iter().zip(reloaded.jobs()) {
+            if old.to_string() != new.to_string() {
+                info!("Changed {} schedule from {} to {}", job, old, new);
+                changed = true;
+            }
+        }
+        let describe = |actions: &[ScheduledAction]| -> Vec<String> {
+            actions
+                .iter()
+                .map(|entry| format!("{} = {}", entry.schedule, entry.action))
+                .collect()
+        };
+        if describe(&previous.actions) != describe(&reloaded.actions) {
+            info!(
+                "Changed scheduled actions from {:?} to {:?}",
+                describe(&previous.actions),
+                describe(&reloaded.actions)
+            );
+            changed = true;
+        }
+        let describe = |windows: &[BlackoutWindow]| -> Vec<String> {
+            windows.iter().map(ToString::to_string).collect()
+        };
+        if describe(&previous.blackouts) != describe(&reloaded.blackouts) {
+            info!(
+                "Changed blackout windows from {:?} to {:?}",
+                describe(&previous.blackouts),
+                describe(&reloaded.blackouts)
+            );
+            changed = true;
+        }
+        if !changed {
+            info!("Reloaded config, schedules are unchanged");
+            return;
+        }
+        // The job choices show the schedules
+        if let Some(ctx) = self.discord_context() {
+            self.register_commands(&ctx).await;
+        }
+    }
+
+    /// The context of the current Discord connection, once there is one.
+    fn discord_context(&self) -> Option<Context> {
+        self.discord.read().unwrap().clone()
+    }
+
+    /// Stops the scheduler so no jobs fire while the bot shuts down.
+    async fn shutdown_scheduler(&self) {
+        let Some(scheduler) = self.job_scheduler.get() else {
+            return;
+        };
+        match scheduler.clone().shutdown().await {
+            Ok(()) => info!("Scheduler stopped"),
+            Err(e) => error!("Failed to stop the scheduler: {}", e),
+        }
+    }
+
+    /// Reloads the schedules whenever the process gets SIGHUP.
+    #[cfg(unix)]
+    fn reload_on_sighup(&self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let handler = self.clone();
+        tokio::spawn(async move {
+            let mut hangups = match signal(SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => {
+                    error!("Failed to listen for SIGHUP: {}", e);
+                    return;
+                }
+            };
+            while hangups.recv().await.is_some() {
+                info!("Received SIGHUP, reloading config");
+                handler.reload_schedules().await;
+            }
+        });
+    }
+
+    /// How much longer automation is paused for, if it is.
+    async fn pause_remaining(&self) -> Option<chrono::Duration> {
+        let paused_until = (*self.paused_until.read().await)?;
+        let remaining = paused_until - Utc::now();
+        (remaining > chrono::Duration::zero()).then_some(remaining)
+    }
+
+    /// When the blackout window covering `time` ends, if there is one. Windows
+    /// are in the timezone of `time`.
+    fn blackout_until<Z: TimeZone>(&self, time: &DateTime<Z>) -> Option<DateTime<Z>> {
+        self.schedules()
+            .blackouts
+            .iter()
+            .filter_map(|window| window.active_until(time))
+            .max()
+    }
+
+    /// Whether a scheduled job should leave the light alone because someone
+    /// switched it by hand recently. Logs which way `SCHEDULE_PRECEDENCE`
+    /// decided.
+    async fn yield_to_manual(&self, job: &str) -> bool {
+        let Some(last) = *self.last_manual_action.read().await else {
+            return false;
+        };
+        let elapsed = last.elapsed();
+        if elapsed >= self.manual_override_window {
+            return false;
+        }
+        match self.schedule_precedence {
+            SchedulePrecedence::Manual => {
+                info!(
+                    "The light was set by hand {}s ago, skipping {} job",
+                    elapsed.as_secs(),
+                    job
+                );
+                true
+            }
+            SchedulePrecedence::Schedule => {
+                info!(
+                    "The light was set by hand {}s ago, running {} job anyway",
+                    elapsed.as_secs(),
+                    job
+                );
+                false
+            }
+        }
+    }
+
+    /// Whether the next run of `job` will be skipped.
+    async fn skip_pending(&self, job: &str) -> bool {
+        self.skipped_jobs
+            .read()
+            .await
+            .get(job)
+            .is_some_and(|&count| count > 0)
+    }
+
+    /// Warns the guilds in `tz` about on and off jobs due in
+    /// `announce_before`, so they have time to skip them.
+    async fn run_announce_job(&self, tz: Tz, guilds: &[GuildId], before: chrono::Duration) {
+        let upcoming = Utc::now().with_timezone(&tz) + before;
+        // The jobs won't run if they're still paused by then
+        if self
+            .paused_until
+            .read()
+            .await
+            .is_some_and(|paused_until| paused_until > upcoming)
+        {
+            return;
+        }
+        if self.blackout_until(&upcoming).is_some() {
+            return;
+        }
+        let schedules = self.schedules();
+        for (job, schedule, action) in [
+            ("off", &schedules.off, "turning off"),
+            ("on", &schedules.on, "turning on"),
+        ] {
+            if !schedule.includes(upcoming)
+                || self.job_disabled(job)
+                || self.skip_pending(job).await
+            {
+                continue;
+            }
+            info!("Announcing {} job in {} for guilds {:?}", job, tz, guilds);
+            self.notify_guilds(
+                guilds,
+                &format!(
+                    "Lights {} in {}. Use `/skip-next {}` to leave them as they are.",
+                    action,
+                    format_duration(before),
+                    job
+                ),
+            )
+            .await;
+        }
+    }
+
+    /// Consumes one pending skip of `job`, returning whether there was one.
+    async fn take_skip(&self, job: &str) -> bool {
+        let mut skipped_jobs = self.skipped_jobs.write().await;
+        match skipped_jobs.get_mut(job) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Adds a job that runs `run` whenever `schedule` is due in `tz`, unless
+    /// automation is paused, a blackout window is active or a skip of `job`
+    /// is pending.
+    async fn add_scheduled_job<F, Fut>(
+        &self,
+        scheduler: &JobScheduler,
+        job: &'static str,
+        tz: Tz,
+        guilds: Vec<GuildId>,
+        schedule: ScheduleFn,
+        run: F,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: Fn(Handler, Tz) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let schedules = self.schedules();
+        info!(
+            "Scheduling {} job at {} (cron {}) in {}",
+            job,
+            schedule(&schedules),
+            schedule(&schedules).crons(),
+            tz
+        );
+        let handler = self.clone();
+        let run = Arc::new(run);
+        // The scheduler only understands UTC, so tick every minute and check
+        // the local time ourselves. This also keeps the jobs right across DST.
+        let ticks = Job::new_async(EVERY_MINUTE, move |_, _| {
+            let handler = handler.clone();
+            let guilds = guilds.clone();
+            let run = run.clone();
+            Box::pin(async move {
+                let fired = Utc::now();
+                let schedules = handler.schedules();
+                let local = fired.with_timezone(&tz);
+                if !schedule(&schedules).includes(local) {
+                    return;
+                }
+                // Nudge forward, since a tick in the due second isn't after it
+                let nudged = local + chrono::Duration::seconds(1);
+                if let Some(scheduled) = schedule(&schedules).previous(&nudged) {
+                    handler.record_drift(
+                        format!("{} ({})", job, tz),
+                        scheduled.with_timezone(&Utc),
+                        fired,
+                    );
+                }
+                if handler.job_disabled(job) {
+                    info!("The {} job is disabled, not running it in {}", job, tz);
+                    return;
+                }
+                if handler.pause_remaining().await.is_some() {
+                    info!("Automation is paused, not running {} job in {}", job, tz);
+                    return;
+                }
+                if let Some(until) = handler.blackout_until(&Utc::now().with_timezone(&tz)) {
+                    info!(
+                        "Blackout window until {}, not running {} job in {}",
+                        until, job, tz
+                    );
+                    return;
+                }
+                if handler.yield_to_manual(job).await {
+                    return;
+                }
+                if handler.take_skip(job).await {
+                    info!("Skipping {} job in {} for guilds {:?}", job, tz, guilds);
+                    return;
+                }
+                info!("Running {} job in {} for guilds {:?}", job, tz, guilds);
+                let _ = run(handler, tz).await;
+            })
+        })?;
+        self.register_job(
+            scheduler,
+            ticks,
+            format!("{} ({})", job, tz),
+            EVERY_MINUTE.to_string(),
+            Some((tz, schedule)),
+        )
+        .await
+    }
+
+    /// Records how long after its scheduled time a job fired.
+    fn record_drift(&self, job: String, scheduled: DateTime<Utc>, fired: DateTime<Utc>) {
+        let mut samples = self.drift_samples.lock().unwrap();
+        if samples.len() == DRIFT_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(DriftSample {
+            job,
+            scheduled,
+            drift: fired - scheduled,
+        });
+    }
+
+    /// Adds a job to the scheduler and records it for `/jobs`.
+    async fn register_job(
+        &self,
+        scheduler: &JobScheduler,
+        job: Job,
+        name: String,
+        trigger: String,
+        schedule: Option<(Tz, ScheduleFn)>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let id = scheduler.add(job).await?;
+        self.registered_jobs.write().unwrap().push(RegisteredJob {
+            id,
+            name,
+            trigger,
+            schedule,
+        });
+        Ok(())
+    }
+
+    /// Runs `action` once at `at`. It runs even if automation is paused,
+    /// since someone asked for it.
+    async fn schedule_one_shot(&self, at: DateTime<Utc>, action: Action) -> Result<(), String> {
+        let Some(scheduler) = self.job_scheduler.get() else {
+            return Err("The scheduler hasn't started yet.".to_string());
+        };
+        let delay = (at - Utc::now()).to_std().unwrap_or_default();
+        let handler = self.clone();
+        let job = Job::new_one_shot_async(delay, move |id, scheduler| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                info!("Running one-time action {}", action);
+                if let Err(e) = handler.apply_action(action).await {
+                    error!("One-time action {} failed: {}", action, e);
+                }
+                handler
+                    .one_shots
+                    .lock()
+                    .await
+                    .retain(|one_shot| one_shot.id != id);
+                handler
+                    .registered_jobs
+                    .write()
+                    .unwrap()
+                    .retain(|job| job.id != id);
+                if let Err(e) = scheduler.remove(&id).await {
+                    debug!("One-time job {} was already removed: {}", id, e);
+                }
+            })
+        })
+        .map_err(|e| format!("Failed to create one-time job: {}", e))?;
+
+        // Track it first, since a job due right away can fire before `add` returns
+        let id = job.guid();
+        self.one_shots.lock().await.push(OneShot { id, at, action });
+        // Box<dyn Error> isn't Send, so don't hold it across an await
+        let registered = self
+            .register_job(
+                scheduler,
+                job,
+                format!("once: {}", action),
+                format!("at {}", at.to_rfc3339()),
+                None,
+            )
+            .await
+            .map_err(|e| e.to_string());
+        if let Err(e) = registered {
+            self.one_shots
+                .lock()
+                .await
+                .retain(|one_shot| one_shot.id != id);
+            return Err(format!("Failed to add one-time job: {}", e));
+        }
+        Ok(())
+    }
+
+    /// Logs when each scheduled job will next run in each timezone.
+    fn log_upcoming_jobs(&self, timezones: &[(Tz, Vec<GuildId>)]) {
+        let schedules = self.schedules();
+        let jobs = schedules.jobs();
+        for (tz, _) in timezones {
+            let now = Utc::now().with_timezone(tz);
+            let upcoming: Vec<String> = jobs
+                .iter()
+                .map(|(job, schedule)| match schedule.next(&now) {
+                    _ if self.job_disabled(job) => format!("{} disabled", job),
+                    Some(next) => format!("{} at {}", job, next.format("%a %Y-%m-%d %H:%M")),
+                    None => format!("{} never", job),
+                })
+                .collect();
+            info!("Upcoming jobs ({}): {}", tz, upcoming.join(", "));
+        }
+    }
+
+    async fn start_scheduler(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let scheduler = JobScheduler::new().await?;
+
+        info!("Current time - UTC: {}", Utc::now());
+
+        let timezones = self.schedule_timezones().await;
+        for (tz, guilds) in timezones.clone() {
+            info!(
+                "Current time - {}: {} (guilds {:?})",
+                tz,
+                Utc::now().with_timezone(&tz),
+                guilds
+            );
+
+            // Turn off lights, at midnight by default
+            self.add_scheduled_job(
+                &scheduler,
+                "off",
+                tz,
+                guilds.clone(),
+                |schedules| &schedules.off,
+                |handler, tz| async move { handler.run_midnight_off_job(tz).await },
+            )
+            .await?;
+
+            // Turn on lights, at 5 PM by default
+            self.add_scheduled_job(
+                &scheduler,
+                "on",
+                tz,
+                guilds.clone(),
+                |schedules| &schedules.on,
+                |handler, tz| async move {
+                    if handler.away_detection && handler.everyone_away().await {
+                        info!("Nobody with the control role is online, leaving the light off");
+                        return Ok(());
+                    }
+                    if let Some(check) = &handler.darkness_check {
+                        if check.is_bright().await {
+                            info!("It's still bright out, leaving the light off");
+                            return Ok(());
+                        }
+                    }
+                    handler.run_evening_on_job(tz).await
+                },
+            )
+            .await?;
+
+            // Warn people before the light switches
+            if let Some(before) = self.announce_before {
+                let handler = self.clone();
+                let guilds = guilds.clone();
+                let job = Job::new_async(EVERY_MINUTE, move |_, _| {
+                    let handler = handler.clone();
+                    let guilds = guilds.clone();
+                    Box::pin(async move {
+                        handler.run_announce_job(tz, &guilds, before).await;
+                    })
+                })?;
+                self.register_job(
+                    &scheduler,
+                    job,
+                    format!("announce ({})", tz),
+                    EVERY_MINUTE.to_string(),
+                    None,
+                )
+                .await?;
+            }
+
+            // Run any extra actions from SCHEDULED_ACTIONS. They're read on
+            // every tick, so a reload can add or remove them.
+            let handler = self.clone();
+            let job = Job::new_async(EVERY_MINUTE, move |_, _| {
+                let handler = handler.clone();
+                Box::pin(async move {
+                    handler.run_scheduled_actions(tz).await;
+                })
+            })?;
+            self.register_job(
+                &scheduler,
+                job,
+                format!("actions ({})", tz),
+                EVERY_MINUTE.to_string(),
+                None,
+            )
+            .await?;
+
+            // Turn the status LED back on in the morning, at 7:00 by default
+            self.add_scheduled_job(
+                &scheduler,
+                "led",
+                tz,
+                guilds,
+                |schedules| &schedules.led_on,
+                |handler, tz| async move { handler.run_morning_led_job(tz).await },
+            )
+            .await?;
+        }
+
+        // Warm bulbs through the evening
+        if self.color_temp_curve.is_some() {
+            let handler = self.clone();
+            let job = Job::new_repeated_async(COLOR_TEMP_INTERVAL, move |_, _| {
+                let handler = handler.clone();
+                Box::pin(async move {
+                    handler.run_color_temp_job().await;
+                })
+            })?;
+            self.register_job(
+                &scheduler,
+                job,
+                "color temperature".to_string(),
+                format!("every {}s", COLOR_TEMP_INTERVAL.as_secs()),
+                None,
+            )
+            .await?;
+        }
+
+        // Periodically reconcile the cached state with the device
+        if let Some(interval) = self.reconcile_interval {
+            let handler = self.clone();
+            let job = Job::new_repeated_async(interval, move |_, _| {
+                let handler = handler.clone();
+                Box::pin(async move {
+                    handler.run_reconcile_job().await;
+                })
+            })?;
+            self.register_job(
+                &scheduler,
+                job,
+                "reconcile".to_string(),
+                format!("every {}s", interval.as_secs()),
+                None,
+            )
+            .await?;
+        }
+
+        // Log the upcoming runs hourly, which also shows the scheduler is alive
+        self.log_upcoming_jobs(&timezones);
+        let handler = self.clone();
+        let job = Job::new_async("0 0 * * * *", move |_, _| {
+            let handler = handler.clone();
+            let timezones = timezones.clone();
+            Box::pin(async move {
+                handler.log_upcoming_jobs(&timezones);
+            })
+        })?;
+        self.register_job(
+            &scheduler,
+            job,
+            "log upcoming".to_string(),
+            "0 0 * * * *".to_string(),
+            None,
+        )
+        .await?;
+
+        // Start the scheduler
+        scheduler.start().await?;
+        let _ = self.job_scheduler.set(scheduler);
+
+        Ok(())
+    }
+
+    /// Sets the brightness picked from the panel's brightness menu.
+    async fn select_brightness(&self, values: &[String]) -> Result<String, String> {
+        let percent = values
+            .first()
+            .and_then(|value| value.parse::<u8>().ok())
+            .filter(|percent| (1..=100).contains(percent))
+            .ok_or_else(|| "Please pick a brightness.".to_string())?;
+        match self
+            .mirrored(|h| async move { h.set_brightness(percent).await })
+            .await
+        {
+            Ok(secondary_error) => {
+                self.fire_action_webhook(Action::Brightness(percent));
+                Ok(with_secondary_result(
+                    format!(
+                        "Brightness set to {}%.{}",
+                        percent,
+                        self.quiet_hours_note(percent)
+                    ),
+                    secondary_error,
+                ))
+            }
+            Err(e) => {
+                error!("Error setting brightness: {}", e);
+                Err("Failed to set brightness".to_string())
+            }
+        }
+    }
+
+    /// Runs the control panel button with the given id, or returns `None` if
+    /// the id isn't one the current panel uses.
+    async fn dispatch_component(&self, custom_id: &str) -> Option<ComponentOutcome> {
+        let result = match custom_id {
+            "light_on" => match self
+                .mirrored(|h| async move { h.turn_on_regular().await })
+                .await
+            {
+                Ok(secondary_error) => {
+                    self.record_light_state(true).await;
+                    Ok(self.with_safety_cutoff(with_secondary_result(
+                        "Light turned on!".to_string(),
+                        secondary_error,
+                    )))
+                }
+                Err(e) => {
+                    error!("Error turning light on: {}", e);
+                    if self.queue_action(Action::On).await {
+                        return Some(ComponentOutcome::Queued(self.queued_message("turn on")));
+                    }
+                    Err("Failed to turn on light".to_string())
+                }
+            },
+            "light_off" => match self.mirrored(|h| async move { h.turn_off().await }).await {
+                Ok(secondary_error) => {
+                    self.record_light_state(false).await;
+                    Ok(with_secondary_result(
+                        "Light turned off!".to_string(),
+                        secondary_error,
+                    ))
+                }
+                Err(e) => {
+                    error!("Error turning light off: {}", e);
+                    if self.queue_action(Action::Off).await {
+                        return Some(ComponentOutcome::Queued(self.queued_message("turn off")));
+                    }
+                    Err("Failed to turn off light".to_string())
+                }
+            },
+            "light_on_15" | "light_on_30" | "light_on_60" => {
+                let minutes: u32 = custom_id["light_on_".len()..]
+                    .parse()
+                    .expect("timed button ids end in a number of minutes");
+                // A shorter safety cutoff still wins
+                let minutes = self.max_on_minutes.map_or(minutes, |max| minutes.min(max));
+                match self.start_timer(minutes).await {
+                    Ok(secondary_error) => {
+                        self.record_light_state(true).await;
+                        let off_time = Utc::now() + chrono::Duration::minutes(minutes.into());
+                        let timestamp = off_time.timestamp();
+                        Ok(with_secondary_result(
+                            format!(
+                                "Light turned on for {} minutes! Will turn off <t:{}:R> (<t:{}:t>)",
+                                minutes, timestamp, timestamp
+                            ),
+                            secondary_error,
+                        ))
+                    }
+                    Err(e) => {
+                        error!("Error setting timed light: {}", e);
+                        Err("Failed to set timed light".to_string())
+                    }
+                }
+            }
+            "led_on" | "led_off" => {
+                let enabled = custom_id == "led_on";
+                match self
+                    .mirrored(|h| async move { h.set_led(enabled).await })
+                    .await
+                {
+                    Ok(secondary_error) => Ok(with_secondary_result(
+                        format!("LED turned {}!", if enabled { "on" } else { "off" }),
+                        secondary_error,
+                    )),
+                    Err(e) if e == NO_LED_FEATURE_ERROR => Err(e),
+                    Err(e) => {
+                        error!("Error setting LED: {}", e);
+                        Err("Failed to set LED".to_string())
+                    }
+                }
+            }
+            custom_id => {
+                let (group, on) = self.group_button(custom_id)?;
+                let results = self.switch_group(group, on).await;
+                self.group_summary(group, on, &results)
+            }
+        };
+        Some(result.into())
+    }
+
+    async fn handle_component(&self, ctx: &Context, component: ComponentInteraction) {
+        // Reboot confirmations live on their own ephemeral message, not the panel
+        if matches!(
+            component.data.custom_id.as_str(),
+            REBOOT_CONFIRM_ID | REBOOT_CANCEL_ID
+        ) {
+            self.handle_reboot_confirmation(ctx, component).await;
+            return;
+        }
+        if matches!(
+            component.data.custom_id.as_str(),
+            WIFI_JOIN_CONFIRM_ID | WIFI_JOIN_CANCEL_ID
+        ) {
+            self.handle_wifi_join_confirmation(ctx, component).await;
+            return;
+        }
+        // The dimmer buttons update their own message rather than replying
+        if matches!(
+            component.data.custom_id.as_str(),
+            DIMMER_DOWN_ID | DIMMER_UP_ID
+        ) {
+            self.handle_dimmer_button(ctx, component).await;
+            return;
+        }
+
+        let rejection = if !self.has_control_role(component.member.as_ref()) {
+            Some(self.missing_role_message())
+        } else if !self.rate_limiter.try_acquire() {
+            Some(RATE_LIMITED_MESSAGE.to_string())
+        } else {
+            None
+        };
+        if let Some(content) = rejection {
+            let content = self.localize(&component.locale, &content);
+            if let Err(why) = component
+                .create_response(&ctx.http, rejection_response(content))
+                .await
+            {
+                error!("Cannot send rejection response: {}", why);
+            }
+            return;
+        }
+
+        // Defer the response without showing a message. The deferred response's
+        // visibility carries over to the followup, so it must match.
+        if let Err(why) = component
+            .create_response(
+                &ctx.http,
+                CreateInteractionResponse::Defer(
+                    CreateInteractionResponseMessage::new().ephemeral(self.responses_ephemeral),
+                ),
+            )
+            .await
+        {
+            error!("Cannot defer button response: {}", why);
+            return;
+        }
+
+        // Process the command. Group buttons also get each light's outcome.
+        let custom_id = component.data.custom_id.as_str();
+        let mut group_embed = None;
+        *self.last_command.write().await = Some(LastCommand {
+            name: format!("button {}", custom_id),
+            at: Utc::now(),
+        });
+        let dispatched = match (&component.data.kind, self.group_button(custom_id)) {
+            (ComponentInteractionDataKind::StringSelect { values }, _)
+                if custom_id == BRIGHTNESS_SELECT_ID =>
+            {
+                Some(self.select_brightness(values).await.into())
+            }
+            (_, Some((group, on))) => {
+                let results = self.switch_group(group, on).await;
+                group_embed = Some(group_results_embed(group, on, &results));
+                Some(self.group_summary(group, on, &results).into())
+            }
+            (_, None) => self.dispatch_component(custom_id).await,
+        };
+        let outcome = match dispatched {
+            Some(outcome) => outcome,
+            None => {
+                // Buttons from a panel created by an older version of the bot
+                warn!(
+                    "Received stale button id {} on message {}, refreshing panel",
+                    custom_id, component.message.id
+                );
+                match component
+                    .channel_id
+                    .edit_message(
+                        &ctx.http,
+                        component.message.id,
+                        EditMessage::new().components(control_panel_components(
+                            &self.groups,
+                            self.brightness_select,
+                        )),
+                    )
+                    .await
+                {
+                    Ok(_) => ComponentOutcome::Failed("That button was out of date, so I've refreshed the controls. Please try again.".to_string()),
+                    Err(e) => {
+                        error!("Failed to refresh stale control panel: {:?}", e);
+                        ComponentOutcome::Failed(
+                            "That button is out of date. Restart the bot to recreate the controls."
+                                .to_string(),
+                        )
+                    }
+                }
+            }
+        };
+
+        // A queued action hasn't changed the light yet
+        if matches!(outcome, ComponentOutcome::Done(_)) {
+            self.usage_stats
+                .record(component.user.id.get(), Utc::now().timestamp())
+                .await;
+            *self.last_manual_action.write().await = Some(Instant::now());
+        }
+
+        self.record_history(
+            format!("button {}", custom_id),
+            component.user.id,
+            outcome.message(),
+        )
+        .await;
+
+        // Briefly react on the control message so everyone can see the outcome
+        if !self.do_not_disturb.load(Ordering::SeqCst) {
+            let reaction = match outcome {
+                ComponentOutcome::Done(_) => '✅',
+                ComponentOutcome::Queued(_) => '⏳',
+                ComponentOutcome::Failed(_) => '❌',
+            };
+            let http = ctx.http.clone();
+            let message = component.message.clone();
+            tokio::spawn(async move {
+                match message.react(&http, reaction).await {
+                    Ok(reaction) => {
+                        tokio::time::sleep(FEEDBACK_REACTION_DURATION).await;
+                        if let Err(e) = reaction.delete(&http).await {
+                            error!("Failed to remove feedback reaction: {:?}", e);
+                        }
+                    }
+                    Err(e) => error!("Failed to add feedback reaction: {:?}", e),
+                }
+            });
+        }
+
+        // Send the final result as a followup
+        let content = self.localize(&component.locale, outcome.message());
+        let mut followup = followup_with_content(content).ephemeral(self.responses_ephemeral);
+        if let Some(embed) = group_embed {
+            followup = followup.embed(embed);
+        }
+        if let Err(why) = component.create_followup(&ctx.http, followup).await {
+            error!("Cannot send followup message: {}", why);
+        }
+    }
+
+    async fn handle_command(&self, ctx: &Context, command: CommandInteraction) {
+        if !NON_DEVICE_COMMANDS.contains(&command.data.name.as_str())
+            && !self.rate_limiter.try_acquire()
+        {
+            if let Err(why) = command
+                .create_response(
+                    &ctx.http,
+                    rejection_response(self.localize(&command.locale, RATE_LIMITED_MESSAGE)),
+                )
+                .await
+            {
+                error!("Cannot send rejection response: {}", why);
+            }
+            return;
+        }
+
+        // Asking for the uptime would otherwise always report itself
+        if command.data.name != "uptime" {
+            *self.last_command.write().await = Some(LastCommand {
+                name: format!("/{}", command.data.name),
+                at: Utc::now(),
+            });
+        }
+
+        // Setup answers with a form rather than a deferred message
+        if command.data.name == "setup" {
+            self.setup_command(ctx, &command).await;
+            return;
+        }
+        // Joining a network asks for confirmation first
+        if command.data.name == "wifi"
+            && command
+                .data
+                .options
+                .first()
+                .is_some_and(|subcommand| subcommand.name == "join")
+        {
+            self.wifi_join_command(ctx, &command).await;
+            return;
+        }
+        // Rebooting asks for confirmation first
+        if command.data.name == "reboot-device" {
+            self.reboot_command(ctx, &command).await;
+            return;
+        }
+        if command.data.name == "stats" {
+            self.stats_command(ctx, &command).await;
+            return;
+        }
+        if command.data.name == "history" {
+            self.history_command(ctx, &command).await;
+            return;
+        }
+        if command.data.name == "about" {
+            self.about_command(ctx, &command).await;
+            return;
+        }
+        if command.data.name == "jobs" {
+            self.jobs_command(ctx, &command).await;
+            return;
+        }
+        if command.data.name == "dimmer" {
+            self.dimmer_command(ctx, &command).await;
+            return;
+        }
+
+        // Light changes are shared like button presses; the rest stay private
+        let name = command.data.name.as_str();
+        let ephemeral = if LIGHT_ACTION_COMMANDS.contains(&name) || name == "nightlight" {
+            self.responses_ephemeral
+        } else {
+            true
+        };
+
+        // Defer the response so slow device commands don't time out the interaction
+        if let Err(why) = command
+            .create_response(
+                &ctx.http,
+                CreateInteractionResponse::Defer(
+                    CreateInteractionResponseMessage::new().ephemeral(ephemeral),
+                ),
+            )
+            .await
+        {
+            error!("Cannot defer command response: {}", why);
+            return;
+        }
+
+        let result = match command.data.name.as_str() {
+            name if LIGHT_ACTION_COMMANDS.contains(&name) => {
+                let result = self.light_action_command(&command).await;
+                // Blinking puts the light back, so only real changes count
+                if result.is_ok() && !matches!(name, "locate" | "notify") {
+                    *self.last_manual_action.write().await = Some(Instant::now());
+                }
+                // Counted like buttons, only when they work
+                if result.is_ok() {
+                    self.usage_stats
+                        .record(command.user.id.get(), Utc::now().timestamp())
+                        .await;
+                }
+                let reply = result.unwrap_or_else(|e| e);
+                self.record_history(format!("/{}", name), command.user.id, &reply)
+                    .await;
+                reply
+            }
+            "run-job" => self.run_job_command(ctx, &command).await,
+            "cloud" => self.cloud_command(ctx, &command).await,
+            "wifi" => self.wifi_scan_command(ctx, &command).await,
+            "skip-next" => self.skip_next_command(&command).await,
+            "shift" => self.shift_command(&command).await,
+            "pause" => self.pause_command(&command).await,
+            "schedule" => self.schedule_command(&command).await,
+            "at" => self.at_command(&command).await,
+            "config" => self.config_command(ctx, &command).await,
+            "rename-channel" => self.rename_channel_command(ctx, &command).await,
+            "timers" => self.timers_command(&command).await,
+            "autooff" => self.auto_off_command(&command).await,
+            "whoami" => self.whoami_command(ctx, &command).await,
+            "on-time" => match self.query_on_since().await {
+                Ok(Some(on_since)) => {
+                    let elapsed = Utc::now().signed_duration_since(on_since);
+                    format!(
+                        "The light has been on for {} (since <t:{}:f>).",
+                        format_duration(elapsed),
+                        on_since.timestamp()
+                    )
+                }
+                Ok(None) => "The light is off.".to_string(),
+                Err(e) if e == NO_ON_TIME_FEATURE_ERROR => e,
+                Err(e) => {
+                    error!("Error reading on time: {}", e);
+                    "Failed to read how long the light has been on".to_string()
+                }
+            },
+            "snapshot" => self.snapshot_command(&command).await,
+            "ping-device" => self.ping_command().await,
+            "nightlight" => self.nightlight_command(&command).await,
+            "dnd" => self.dnd_command(&command),
+            "uptime" => self.uptime_command(&command).await,
+            "drift" => self.drift_command(),
+            "cron-check" => self.cron_check_command(&command),
+            "reload-credentials" => self.reload_credentials_command(ctx, &command).await,
+            "status" => match self.execute_light_command(&self.device, &["state"]).await {
+                Ok(stdout) => {
+                    let heading = format!("**{}** ({})", self.device.name, self.device.kind);
+                    match self.readings_summary().await {
+                        Some(summary) => {
+                            format!("{}\n{}\n```\n{}\n```", heading, summary, stdout.trim())
+                        }
+                        None => format!("{}\n```\n{}\n```", heading, stdout.trim()),
+                    }
+                }
+                Err(e) => {
+                    error!("Error reading device status: {}", e);
+                    format!("Failed to read device status: {}", e)
+                }
+            },
+            _ => "Unknown command".to_string(),
+        };
+
+        let result = self.localize(&command.locale, &result);
+        if let Err(why) = command
+            .create_followup(
+                &ctx.http,
+                followup_with_content(result).ephemeral(ephemeral),
+            )
+            .await
+        {
+            error!("Cannot send followup message: {}", why);
+        }
+    }
+
+    /// Runs one of `LIGHT_ACTION_COMMANDS`, failing if the user may not or
+    /// the light didn't respond.
+    async fn light_action_command(&self, command: &CommandInteraction) -> Result<String, String> {
+        if !self.has_control_role(command.member.as_deref()) {
+            return Err(self.missing_role_message());
+        }
+
+        match command.data.name.as_str() {
+            TOGGLE_LIGHT_COMMAND => self.toggle_command().await,
+            "sunrise" => match self.start_sunrise().await {
+                Ok(secondary_error) => {
+                    self.record_light_state(true).await;
+                    Ok(with_secondary_result(
+                        format!(
+                            "Sunrise started! The light will reach full brightness in {} minutes.",
+                            self.sunrise_duration.as_secs() / 60
+                        ),
+                        secondary_error,
+                    ))
+                }
+                Err(e) => {
+                    error!("Error starting sunrise: {}", e);
+                    Err("Failed to start sunrise".to_string())
+                }
+            },
+            "notify" => Ok(self.notify_command(command)),
+            "temp-bright" => self.temp_brightness_command(command).await,
+            "locate" => {
+                // Flashing takes a few seconds, so reply right away
+                let handler = self.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handler.blink(LOCATE_FLASHES).await {
+                        error!("Error flashing light: {}", e);
+                    }
+                });
+                Ok("Flashing the light so you can find it...".to_string())
+            }
+            _ => Err("Unknown command".to_string()),
+        }
+    }
+
+    async fn toggle_command(&self) -> Result<String, String> {
+        match self.toggle_light().await {
+            Ok((on, secondary_error)) => {
+                self.record_light_state(on).await;
+                let message = with_secondary_result(
+                    format!("Light turned {}!", if on { "on" } else { "off" }),
+                    secondary_error,
+                );
+                if on {
+                    Ok(self.with_safety_cutoff(message))
+                } else {
+                    Ok(message)
+                }
+            }
+            Err(e) => {
+                error!("Error toggling light: {}", e);
+                Err("Failed to toggle light".to_string())
+            }
+        }
+    }
+
+    async fn stats_command(&self, ctx: &Context, command: &CommandInteraction) {
+        let leaderboard = self.usage_stats.leaderboard().await;
+        let total: u64 = leaderboard.iter().map(|(_, stats)| stats.actions).sum();
+        let description = if leaderboard.is_empty() {
+            "Nobody has used the light yet.".to_string()
+        } else {
+            leaderboard
+                .iter()
+                .take(STATS_LEADERBOARD_SIZE)
+                .enumerate()
+                .map(|(rank, (user_id, stats))| {
+                    format!(
+                        "{}. <@{}>: {} action{}, last <t:{}:R>",
+                        rank + 1,
+                        user_id,
+                        stats.actions,
+                        if stats.actions == 1 { "" } else { "s" },
+                        stats.last_used
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let embed = CreateEmbed::new()
+            .title("Light usage")
+            .description(description)
+            .footer(CreateEmbedFooter::new(format!(
+                "{} actions in total",
+                total
+            )));
+
+        if let Err(why) = command
+            .create_response(
+                &ctx.http,
+                CreateInteractionResponse::Message(
+                    CreateInteractionResponseMessage::new()
+                        .embed(embed)
+                        .ephemeral(true),
+                ),
+            )
+            .await
+        {
+            error!("Cannot send stats: {}", why);
+        }
+    }
+
+    async fn history_command(&self, ctx: &Context, command: &CommandInteraction) {
+        let count = command
+            .data
+            .options
+            .first()
+            .and_then(|option| option.value.as_i64())
+            .map_or(DEFAULT_HISTORY_COUNT, |count| {
+                count.clamp(1, MAX_HISTORY_COUNT as i64) as usize
+            });
+        let tz = self.guild_timezone(command.guild_id);
+        let entries = self.history.recent(count).await;
+        let description = if entries.is_empty() {
+            "Nobody has done anything yet.".to_string()
+        } else {
+            entries
+                .iter()
+                .map(|entry| {
+                    let at = DateTime::from_timestamp(entry.at, 0)
+                        .map(|at| at.with_timezone(&tz).format("%a %H:%M").to_string())
+                        .unwrap_or_default();
+                    format!(
+                        "`{}` <@{}> **{}**: {}",
+                        at, entry.user_id, entry.action, entry.outcome
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let embed = CreateEmbed::new()
+            .title("Recent actions")
+            .description(description)
+            .footer(CreateEmbedFooter::new(format!("Times in {}", tz)));
+
+        if let Err(why) = command
+            .create_response(
+                &ctx.http,
+                CreateInteractionResponse::Message(
+                    CreateInteractionResponseMessage::new()
+                        .embed(embed)
+                        .ephemeral(true),
+                ),
+            )
+            .await
+        {
+            error!("Cannot send history: {}", why);
+        }
+    }
+
+    /// Adds an action and the first line of its reply to the history.
+    async fn record_history(&self, action: String, user_id: UserId, reply: &str) {
+        let outcome: String = reply
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .take(100)
+            .collect();
+        self.history
+            .record(HistoryEntry {
+                action,
+                user_id: user_id.get(),
+                at: Utc::now().timestamp(),
+                outcome,
+            })
+            .await;
+    }
+
+    fn dnd_command(&self, command: &CommandInteraction) -> String {
+        if !self.has_control_role(command.member.as_deref()) {
+            return self.missing_role_message();
+        }
+
+        let enabled = command
+            .data
+            .options
+            .first()
+            .and_then(|option| option.value.as_str())
+            == Some("on");
+        self.do_not_disturb.store(enabled, Ordering::SeqCst);
+        info!(
+            "{} turned do not disturb {}",
+            command.user.name,
+            if enabled { "on" } else { "off" }
+        );
+        if enabled {
+            "Do not disturb is on. Commands still work, but the bot won't post alerts, \
+             announcements or reactions until it's turned off."
+                .to_string()
+        } else {
+            "Do not disturb is off.".to_string()
+        }
+    }
+
+    async fn nightlight_command(&self, command: &CommandInteraction) -> String {
+        if !self.has_control_role(command.member.as_deref()) {
+            return self.missing_role_message();
+        }
+
+        let enabled = command
+            .data
+            .options
+            .first()
+            .and_then(|option| option.value.as_str())
+            == Some("on");
+        let state = if enabled { "on" } else { "off" };
+        match self.set_nightlight(enabled).await {
+            Ok(()) => format!("Nightlight turned {}!", state),
+            Err(e) if e == NO_NIGHTLIGHT_FEATURE_ERROR => e,
+            Err(e) => {
+                error!("Error setting nightlight: {}", e);
+                "Failed to set the nightlight".to_string()
+            }
+        }
+    }
+
+    /// Parses a cron expression the way the scheduler does and lists its
+    /// next runs in the guild's timezone.
+    fn cron_check_command(&self, command: &CommandInteraction) -> String {
+        let expression = command
+            .data
+            .options
+            .first()
+            .and_then(|option| option.value.as_str())
+            .unwrap_or_default()
+            .trim();
+        let schedule = match expression.parse::<cron::Schedule>() {
+            Ok(schedule) => schedule,
+            Err(e) => return format!("`{}` isn't a valid cron expression: {}", expression, e),
+        };
+        let tz = self.guild_timezone(command.guild_id);
+        let runs: Vec<String> = schedule
+            .upcoming(tz)
+            .take(CRON_CHECK_RUNS)
+            .map(|run| {
+                format!(
+                    "{} (<t:{}:R>)",
+                    run.format("%a %Y-%m-%d %H:%M:%S"),
+                    run.timestamp()
+                )
+            })
+            .collect();
+        if runs.is_empty() {
+            return format!("`{}` is valid but never fires.", expression);
+        }
+        format!(
+            "`{}` next fires in {} at:\n{}",
+            expression,
+            tz,
+            runs.join("\n")
+        )
+    }
+
+    fn drift_command(&self) -> String {
+        let samples = self.drift_samples.lock().unwrap();
+        let Some(worst) = samples.iter().max_by_key(|sample| sample.drift) else {
+            return "No scheduled jobs have fired yet.".to_string();
+        };
+        let total: i64 = samples
+            .iter()
+            .map(|sample| sample.drift.num_milliseconds())
+            .sum();
+        let mut lines = vec![
+            format!("**Scheduler drift** (last {} fires)", samples.len()),
+            format!(
+                "Average {}ms, worst {}ms ({} at <t:{}:t>)",
+                total / samples.len() as i64,
+                worst.drift.num_milliseconds(),
+                worst.job,
+                worst.scheduled.timestamp()
+            ),
+        ];
+        lines.extend(samples.iter().rev().take(DRIFT_RECENT).map(|sample| {
+            format!(
+                "{} at <t:{}:t>: {}ms",
+                sample.job,
+                sample.scheduled.timestamp(),
+                sample.drift.num_milliseconds()
+            )
+        }));
+        lines.join("\n")
+    }
+
+    async fn uptime_command(&self, command: &CommandInteraction) -> String {
+        let tz = self.guild_timezone(command.guild_id);
+        let uptime = self.started_at.elapsed();
+        let started = Utc::now() - chrono::Duration::from_std(uptime).unwrap_or_default();
+        let format = |time: DateTime<Utc>| {
+            time.with_timezone(&tz)
+                .format("%a %Y-%m-%d %H:%M %Z")
+                .to_string()
+        };
+        let last_command = match &*self.last_command.read().await {
+            Some(last) => format!("Last used: {} at {}.", last.name, format(last.at)),
+            None => "Nothing has been used since then.".to_string(),
+        };
+        format!(
+            "Up for {}, since {}.\n{}",
+            format_duration(chrono::Duration::from_std(uptime).unwrap_or_default()),
+            format(started),
+            last_command
+        )
+    }
+
+    async fn about_command(&self, ctx: &Context, command: &CommandInteraction) {
+        if let Err(why) = command
+            .create_response(
+                &ctx.http,
+                CreateInteractionResponse::Defer(
+                    CreateInteractionResponseMessage::new().ephemeral(true),
+                ),
+            )
+            .await
+        {
+            error!("Cannot defer about response: {}", why);
+            return;
+        }
+
+        let mut embed = CreateEmbed::new()
+            .title(format!("{} ({})", self.device.name, self.device.kind))
+            .footer(CreateEmbedFooter::new(format!(
+                "{} v{}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )));
+        match self.query_sysinfo().await {
+            Ok(sysinfo) => {
+                for (label, value) in [
+                    ("Model", &sysinfo.model),
+                    ("Firmware", &sysinfo.sw_ver),
+                    ("Hardware", &sysinfo.hw_ver),
+                ] {
+                    let value = if value.is_empty() { "unknown" } else { value };
+                    embed = embed.field(label, value, true);
+                }
+            }
+            Err(e) => {
+                error!("Error reading sysinfo: {}", e);
+                embed = embed.description("Couldn't reach the device for its details.");
+            }
+        }
+
+        if let Err(why) = command
+            .edit_response(&ctx.http, EditInteractionResponse::new().embed(embed))
+            .await
+        {
+            error!("Cannot send about: {}", why);
+        }
+    }
+
+    async fn dimmer_command(&self, ctx: &Context, command: &CommandInteraction) {
+        if !self.has_control_role(command.member.as_deref()) {
+            let content = self.localize(&command.locale, &self.missing_role_message());
+            if let Err(why) = command
+                .create_response(&ctx.http, rejection_response(content))
+                .await
+            {
+                error!("Cannot send rejection response: {}", why);
+            }
+            return;
+        }
+        if let Err(why) = command
+            .create_response(
+                &ctx.http,
+                CreateInteractionResponse::Defer(
+                    CreateInteractionResponseMessage::new().ephemeral(true),
+                ),
+            )
+            .await
+        {
+            error!("Cannot defer dimmer response: {}", why);
+            return;
+        }
+
+        let response = match self.query_brightness().await {
+            Ok(percent) => EditInteractionResponse::new()
+                .content(format!("{} brightness", self.device.name))
+                .components(dimmer_components(percent)),
+            Err(e) => {
+                error!("Error reading brightness for dimmer: {}", e);
+                EditInteractionResponse::new().content(format!(
+                    "Couldn't read {}'s brightness, so there's nothing to adjust.",
+                    self.device.name
+                ))
+            }
+        };
+        if let Err(why) = command.edit_response(&ctx.http, response).await {
+            error!("Cannot send dimmer: {}", why);
+        }
+    }
+
+    /// Steps the brightness up or down and redraws the dimmer with the new
+    /// value.
+    async fn handle_dimmer_button(&self, ctx: &Context, component: ComponentInteraction) {
+        let rejection = if !self.has_control_role(component.member.as_ref()) {
+            Some(self.missing_role_message())
+        } else if !self.rate_limiter.try_acquire() {
+            Some(RATE_LIMITED_MESSAGE.to_string())
+        } else {
+            None
+        };
+        if let Some(content) = rejection {
+            let content = self.localize(&component.locale, &content);
+            if let Err(why) = component
+                .create_response(&ctx.http, rejection_response(content))
+                .await
+            {
+                error!("Cannot send rejection response: {}", why);
+            }
+            return;
+        }
+        if let Err(why) = component
+            .create_response(&ctx.http, CreateInteractionResponse::Acknowledge)
+            .await
+        {
+            error!("Cannot acknowledge dimmer button: {}", why);
+            return;
+        }
+
+        let up = component.data.custom_id == DIMMER_UP_ID;
+        let result = match self.query_brightness().await {
+            Ok(current) => {
+                let percent = if up {
+                    current.saturating_add(DIMMER_STEP).min(100)
+                } else {
+                    current.saturating_sub(DIMMER_STEP).max(1)
+                };
+                self.mirrored(|h| async move { h.set_brightness(percent).await })
+                    .await
+                    .map(|secondary_error| (percent, secondary_error))
+            }
+            Err(e) => Err(e),
+        };
+        let response = match result {
+            Ok((percent, secondary_error)) => {
+                self.fire_action_webhook(Action::Brightness(percent));
+                *self.last_manual_action.write().await = Some(Instant::now());
+                let content = with_secondary_result(
+                    format!(
+                        "{} brightness{}",
+                        self.device.name,
+                        self.quiet_hours_note(percent)
+                    ),
+                    secondary_error,
+                );
+                self.record_history(
+                    format!("dimmer {}", if up { "+" } else { "−" }),
+                    component.user.id,
+                    &content,
+                )
+                .await;
+                EditInteractionResponse::new()
+                    .content(self.localize(&component.locale, &content))
+                    .components(dimmer_components(self.quiet_hours_cap(percent)))
+            }
+            Err(e) => {
+                error!("Error adjusting brightness from dimmer: {}", e);
+                let content = self.localize(&component.locale, "Failed to set brightness");
+                EditInteractionResponse::new().content(content)
+            }
+        };
+        if let Err(why) = component.edit_response(&ctx.http, response).await {
+            error!("Cannot update dimmer: {}", why);
+        }
+    }
+
+    async fn jobs_command(&self, ctx: &Context, command: &CommandInteraction) {
+        if !self.is_owner(ctx, command.user.id).await {
+            if let Err(why) = command
+                .create_response(
+                    &ctx.http,
+                    rejection_response("This command is restricted to the bot owner.".to_string()),
+                )
+                .await
+            {
+                error!("Cannot send rejection response: {}", why);
+            }
+            return;
+        }
+
+        let schedules = self.schedules();
+        let jobs: Vec<(Uuid, String, String, Option<DateTime<Utc>>)> = self
+            .registered_jobs
+            .read()
+            .unwrap()
+            .iter()
+            .map(|job| {
+                // Jobs that tick every minute only act when their schedule is
+                // due, so report that instead of the next tick
+                let (trigger, next) = match job.schedule {
+                    Some((tz, schedule)) => (
+                        format!("`{}` in {}", schedule(&schedules), tz),
+                        schedule(&schedules)
+                            .next(&Utc::now().with_timezone(&tz))
+                            .map(|next| next.with_timezone(&Utc)),
+                    ),
+                    None => (format!("`{}`", job.trigger), None),
+                };
+                (job.id, job.name.clone(), trigger, next)
+            })
+            .collect();
+
+        let mut embed = CreateEmbed::new().title("Scheduled jobs");
+        if jobs.is_empty() {
+            embed = embed.description("The scheduler hasn't started.");
+        }
+        for (id, name, trigger, next) in jobs {
+            let next = match next {
+                Some(next) => Some(next),
+                None => match self.job_scheduler.get() {
+                    Some(scheduler) => scheduler.clone().next_tick_for_job(id).await.ok().flatten(),
+                    None => None,
+                },
+            };
+            let next = match next {
+                Some(next) => format!("next <t:{}:R>", next.timestamp()),
+                None => "no upcoming run".to_string(),
+            };
+            embed = embed.field(name, format!("{}\n{}", trigger, next), false);
+        }
+
+        if let Err(why) = command
+            .create_response(
+                &ctx.http,
+                CreateInteractionResponse::Message(
+                    CreateInteractionResponseMessage::new()
+                        .embed(embed)
+                        .ephemeral(true),
+                ),
+            )
+            .await
+        {
+            error!("Cannot send jobs: {}", why);
+        }
+    }
+
+    async fn reboot_command(&self, ctx: &Context, command: &CommandInteraction) {
+        let response = if self.is_owner(ctx, command.user.id).await {
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(format!(
+                        "Reboot {}? It will lose power for a moment.",
+                        self.device.name
+                    ))
+                    .components(vec![CreateActionRow::Buttons(vec![
+                        CreateButton::new(REBOOT_CONFIRM_ID)
+                            .label("Reboot")
+                            .style(ButtonStyle::Danger),
+                        CreateButton::new(REBOOT_CANCEL_ID)
+                            .label("Cancel")
+                            .style(ButtonStyle::Secondary),
+                    ])])
+                    .ephemeral(true),
+            )
+        } else {
+            rejection_response("This command is restricted to the bot owner.".to_string())
+        };
+
+        if let Err(why) = command.create_response(&ctx.http, response).await {
+            error!("Cannot respond to reboot command: {}", why);
+        }
+    }
+
+    async fn wifi_join_command(&self, ctx: &Context, command: &CommandInteraction) {
+        let response = if self.is_owner(ctx, command.user.id).await {
+            let options = match command.data.options.first().map(|option| &option.value) {
+                Some(CommandDataOptionValue::SubCommand(options)) => options.as_slice(),
+                _ => &[],
+            };
+            let option = |name: &str| options.iter().find(|option| option.name == name);
+            let join = WifiJoin {
+                ssid: option("ssid")
+                    .and_then(|option| option.value.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                password: option("password")
+                    .and_then(|option| option.value.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                key_type: option("security")
+                    .and_then(|option| option.value.as_i64())
+                    .unwrap_or(DEFAULT_WIFI_KEY_TYPE),
+            };
+            let content = format!(
+                "Move {} to the {} network? If the details are wrong it will drop off this \
+                 network and need setting up again with the Kasa app.",
+                self.device.name, join.ssid
+            );
+            self.pending_wifi_joins
+                .lock()
+                .unwrap()
+                .insert(command.user.id, join);
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(content)
+                    .components(vec![CreateActionRow::Buttons(vec![
+                        CreateButton::new(WIFI_JOIN_CONFIRM_ID)
+                            .label("Join")
+                            .style(ButtonStyle::Danger),
+                        CreateButton::new(WIFI_JOIN_CANCEL_ID)
+                            .label("Cancel")
+                            .style(ButtonStyle::Secondary),
+                    ])])
+                    .ephemeral(true),
+            )
+        } else {
+            rejection_response("This command is restricted to the bot owner.".to_string())
+        };
+
+        if let Err(why) = command.create_response(&ctx.http, response).await {
+            error!("Cannot respond to wifi join command: {}", why);
+        }
+    }
+
+    async fn handle_wifi_join_confirmation(&self, ctx: &Context, component: ComponentInteraction) {
+        let confirmed = component.data.custom_id == WIFI_JOIN_CONFIRM_ID;
+        let is_owner = self.is_owner(ctx, component.user.id).await;
+        let join = if is_owner {
+            self.pending_wifi_joins
+                .lock()
+                .unwrap()
+                .remove(&component.user.id)
+        } else {
+            None
+        };
+        let content = match (is_owner, confirmed, &join) {
+            (false, _, _) => "This command is restricted to the bot owner.",
+            (true, _, None) => "That request has already been handled.",
+            (true, true, Some(_)) => "Joining...",
+            (true, false, Some(_)) => "Network change cancelled.",
+        };
+        if let Err(why) = component
+            .create_response(
+                &ctx.http,
+                CreateInteractionResponse::UpdateMessage(
+                    CreateInteractionResponseMessage::new()
+                        .content(content)
+                        .components(Vec::new()),
+                ),
+            )
+            .await
+        {
+            error!("Cannot respond to wifi join confirmation: {}", why);
+            return;
+        }
+        let Some(join) = join.filter(|_| confirmed) else {
+            return;
+        };
+
+        info!(
+            "{} is moving {} to the {} network",
+            component.user.name, self.device.name, join.ssid
+        );
+        let content = match self.join_wifi(&join).await {
+            Ok(output) => format!(
+                "{} was told to join {} and is leaving this network. Update KASA_DEVICE_IP \
+                 if its address changes.\n```\n{}\n```",
+                self.device.name, join.ssid, output
+            ),
+            Err(e) => {
+                error!("Error joining Wi-Fi network: {}", e);
+                format!(
+                    "Joining {} failed: {}\nIf {} did switch networks, it won't answer here \
+                     anymore.",
+                    join.ssid, e, self.device.name
+                )
+            }
+        };
+        if let Err(why) = component
+            .edit_response(&ctx.http, EditInteractionResponse::new().content(content))
+            .await
+        {
+            error!("Cannot send wifi join result: {}", why);
+        }
+    }
+
+    async fn handle_reboot_confirmation(&self, ctx: &Context, component: ComponentInteraction) {
+        let confirmed = component.data.custom_id == REBOOT_CONFIRM_ID;
+        let is_owner = self.is_owner(ctx, component.user.id).await;
+        let content = match (is_owner, confirmed) {
+            (false, _) => "This command is restricted to the bot owner.",
+            (true, true) => "Rebooting...",
+            (true, false) => "Reboot cancelled.",
+        };
+        if let Err(why) = component
+            .create_response(
+                &ctx.http,
+                CreateInteractionResponse::UpdateMessage(
+                    CreateInteractionResponseMessage::new()
+                        .content(content)
+                        .components(Vec::new()),
+                ),
+            )
+            .await
+        {
+            error!("Cannot respond to reboot confirmation: {}", why);
+            return;
+        }
+        if !(is_owner && confirmed) {
+            return;
+        }
+
+        info!("{} is rebooting {}", component.user.name, self.device.name);
+        let content = match self.reboot_device().await {
+            Ok(elapsed) => format!(
+                "{} rebooted and was back after {}s.",
+                self.device.name,
+                elapsed.as_secs()
+            ),
+            Err(e) => {
+                error!("Error rebooting device: {}", e);
+                e
+            }
+        };
+        if let Err(why) = component
+            .edit_response(&ctx.http, EditInteractionResponse::new().content(content))
+            .await
+        {
+            error!("Cannot report reboot result: {}", why);
+        }
+    }
+
+    /// Reboots the device and waits for it to respond again, returning how
+    /// long that took.
+    async fn reboot_device(&self) -> Result<Duration, String> {
+        self.execute_light_command(&self.device, &["reboot"])
+            .await
+            .map_err(|e| format!("Failed to reboot {}: {}", self.device.name, e))?;
+
+        // Poll the controller directly so the expected downtime doesn't
+        // trigger retries or failure notifications
+        let started = Instant::now();
+        while started.elapsed() < REBOOT_TIMEOUT {
+            tokio::time::sleep(REBOOT_POLL_INTERVAL).await;
+            if let Ok(stdout) = self
+                .controller
+                .run(&self.device, &["--json", "sysinfo"])
+                .await
+            {
+                if let Some(on) = parse_json::<SysInfo>(&stdout)
+                    .ok()
+                    .and_then(|sysinfo| sysinfo.is_on())
+                {
+                    self.record_light_state(on).await;
+                }
+                return Ok(started.elapsed());
+            }
+        }
+        Err(format!(
+            "{} hasn't come back {}s after rebooting. Check on it.",
+            self.device.name,
+            REBOOT_TIMEOUT.as_secs()
+        ))
+    }
+
+    async fn setup_command(&self, ctx: &Context, command: &CommandInteraction) {
+        let response = if self.is_owner(ctx, command.user.id).await {
+            let schedules = self.schedules();
+            let input = |custom_id: &str, label: &str, value: &str| {
+                CreateActionRow::InputText(
+                    CreateInputText::new(InputTextStyle::Short, label, custom_id).value(value),
+                )
+            };
+            CreateInteractionResponse::Modal(
+                CreateModal::new(SETUP_MODAL_ID, "Light setup").components(vec![
+                    input("host", "Device IP address", &self.device.host),
+                    input(
+                        "username",
+                        "Kasa username",
+                        &self.device.credentials().username,
+                    ),
+                    CreateActionRow::InputText(
+                        CreateInputText::new(InputTextStyle::Short, "Kasa password", "password")
+                            .placeholder("Leave blank to keep the current password")
+                            .required(false),
+                    ),
+                    input("timezone", "Schedule timezone", self.timezone.name()),
+                    // Discord allows five fields, so both schedules share one
+                    input(
+                        "schedule",
+                        "On; off schedule, like 17:00; 00:00",
+                        &format!("{}; {}", schedules.on, schedules.off),
+                    ),
+                ]),
+            )
+        } else {
+            rejection_response("This command is restricted to the bot owner.".to_string())
+        };
+
+        if let Err(why) = command.create_response(&ctx.http, response).await {
+            error!("Cannot respond to setup command: {}", why);
+        }
+    }
+
+    async fn handle_modal(&self, ctx: &Context, modal: ModalInteraction) {
+        if modal.data.custom_id != SETUP_MODAL_ID {
+            return;
+        }
+
+        if let Err(why) = modal
+            .create_response(
+                &ctx.http,
+                CreateInteractionResponse::Defer(
+                    CreateInteractionResponseMessage::new().ephemeral(true),
+                ),
+            )
+            .await
+        {
+            error!("Cannot defer setup response: {}", why);
+            return;
+        }
+
+        let content = if self.is_owner(ctx, modal.user.id).await {
+            match self.apply_setup(&modal).await {
+                Ok(()) => format!(
+                    "Reached the light and saved the settings to `{}`. Restart the bot to use them.",
+                    ENV_FILE
+                ),
+                Err(e) => e,
+            }
+        } else {
+            "This command is restricted to the bot owner.".to_string()
+        };
+
+        if let Err(why) = modal
+            .create_followup(
+                &ctx.http,
+                CreateInteractionResponseFollowup::new()
+                    .content(content)
+                    .ephemeral(true),
+            )
+            .await
+        {
+            error!("Cannot send setup followup: {}", why);
+        }
+    }
+
+    /// Checks the submitted setup form against the device and saves it.
+    async fn apply_setup(&self, modal: &ModalInteraction) -> Result<(), String> {
+        let field = |custom_id: &str| -> String {
+            modal
+                .data
+                .components
+                .iter()
+                .flat_map(|row| &row.components)
+                .find_map(|component| match component {
+                    ActionRowComponent::InputText(input) if input.custom_id == custom_id => {
+                        input.value.clone()
+                    }
+                    _ => None,
+                })
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        };
+        let host = field("host");
+        let username = field("username");
+        let password = match field("password") {
+            password if password.is_empty() => self.device.credentials().password,
+            password => password,
+        };
+        let timezone = field("timezone");
+        if timezone.parse::<Tz>().is_err() {
+            return Err(format!(
+                "`{}` isn't a timezone I know. Use a name like America/Toronto.",
+                timezone
+            ));
+        }
+        let schedule = field("schedule");
+        let Some((on_schedule, off_schedule)) = schedule.split_once(';') else {
+            return Err(format!(
+                "`{}` needs an on and an off schedule separated by `;`, like `17:00; 00:00`.",
+                schedule
+            ));
+        };
+        let (on_schedule, off_schedule) = (on_schedule.trim(), off_schedule.trim());
+        for (job, value) in [("on", on_schedule), ("off", off_schedule)] {
+            if let Err(e) = value.parse::<JobSchedule>() {
+                return Err(format!(
+                    "The {} schedule `{}` is invalid: {}",
+                    job, value, e
+                ));
+            }
+        }
+
+        let device = Device {
+            host,
+            credentials: Arc::new(std::sync::RwLock::new(Credentials { username, password })),
+            ..self.device.clone()
+        };
+        // Talk to the controller directly so a typo doesn't trigger retries or
+        // failure notifications
+        if let Err(e) = self.controller.run(&device, &["state"]).await {
+            error!("Setup couldn't reach {}: {}", device.host, e);
+            return Err(format!(
+                "Couldn't reach the light at {} with those credentials, so nothing was saved.",
+                device.host
+            ));
+        }
+
+        info!("Saving setup for device at {}", device.host);
+        let credentials = device.credentials();
+        save_env_vars(&[
+            ("KASA_DEVICE_IP", &device.host),
+            ("KASA_USERNAME", &credentials.username),
+            ("KASA_PASSWORD", &credentials.password),
+            ("TIMEZONE", &timezone),
+            ("ON_SCHEDULE", on_schedule),
+            ("OFF_SCHEDULE", off_schedule),
+        ])
+    }
+
+    async fn cloud_command(&self, ctx: &Context, command: &CommandInteraction) -> String {
+        if !self.is_owner(ctx, command.user.id).await {
+            return "This command is restricted to the bot owner.".to_string();
+        }
+
+        let enabled = command
+            .data
+            .options
+            .first()
+            .and_then(|option| option.value.as_str())
+            == Some("on");
+
+        info!(
+            "{} is turning the cloud connection {}",
+            command.user.name,
+            if enabled { "on" } else { "off" }
+        );
+        match self.set_cloud_connection(enabled).await {
+            Ok(connected) => format!(
+                "Cloud connection is now {}.",
+                if connected { "on" } else { "off" }
+            ),
+            Err(e) => {
+                error!("Error setting cloud connection: {}", e);
+                format!("Failed to set cloud connection: {}", e)
+            }
+        }
+    }
+
+    async fn wifi_scan_command(&self, ctx: &Context, command: &CommandInteraction) -> String {
+        if !self.is_owner(ctx, command.user.id).await {
+            return "This command is restricted to the bot owner.".to_string();
+        }
+
+        match self.scan_wifi().await {
+            Ok(networks) => format!(
+                "Networks {} can see:\n```\n{}\n```",
+                self.device.name, networks
+            ),
+            Err(e) => {
+                error!("Error scanning for Wi-Fi networks: {}", e);
+                format!("Failed to scan for Wi-Fi networks: {}", e)
+            }
+        }
+    }
+
+    async fn skip_next_command(&self, command: &CommandInteraction) -> String {
+        if !self.has_control_role(command.member.as_deref()) {
+            return self.missing_role_message();
+        }
+
+        let schedules = self.schedules();
+        let (job, description) = match command
+            .data
+            .options
+            .first()
+            .and_then(|option| option.value.as_str())
+        {
+            Some("on") => ("on", format!("on ({})", schedules.on)),
+            Some("off") => ("off", format!("off ({})", schedules.off)),
+            other => return format!("Unknown job: {}", other.unwrap_or_default()),
+        };
+
+        let pending = {
+            let mut skipped_jobs = self.skipped_jobs.write().await;
+            let count = skipped_jobs.entry(job).or_default();
+            *count += 1;
+            *count
+        };
+        info!(
+            "{} skipped the next {} job ({} pending)",
+            command.user.name, job, pending
+        );
+        if pending == 1 {
+            format!("The next {} job will be skipped.", description)
+        } else {
+            format!("The next {} {} jobs will be skipped.", pending, description)
+        }
+    }
+
+    async fn temp_brightness_command(
+        &self,
+        command: &CommandInteraction,
+    ) -> Result<String, String> {
+        let option = |name: &str| {
+            command
+                .data
+                .options
+                .iter()
+                .find(|option| option.name == name)
+                .and_then(|option| option.value.as_i64())
+        };
+        let (Some(percent), Some(minutes)) = (option("percent"), option("minutes")) else {
+            return Err("Please give a brightness and a number of minutes.".to_string());
+        };
+        let percent = percent.clamp(1, 100) as u8;
+        let duration = Duration::from_secs(60 * minutes.max(1) as u64);
+
+        match self
+            .mirrored(|h| async move { h.set_temp_brightness(percent, duration).await })
+            .await
+        {
+            Ok(secondary_error) => {
+                self.fire_action_webhook(Action::Brightness(percent));
+                Ok(with_secondary_result(
+                    format!(
+                        "Brightness set to {}% for {} minutes.{}",
+                        percent,
+                        duration.as_secs() / 60,
+                        self.quiet_hours_note(percent)
+                    ),
+                    secondary_error,
+                ))
+            }
+            Err(e) => {
+                error!("Error setting temporary brightness: {}", e);
+                Err("Failed to set temporary brightness".to_string())
+            }
+        }
+    }
+
+    /// Asks `device` for its sysinfo, returning how long it took to answer.
+    async fn ping_device(&self, device: &Device) -> Result<Duration, String> {
+        // Go straight to the controller so a failure isn't retried
+        let started = Instant::now();
+        let timeout = device.timeout.unwrap_or(self.kasa_timeout);
+        let secs = timeout.as_secs().to_string();
+        tokio::time::timeout(
+            timeout,
+            self.controller
+                .run(device, &["--timeout", &secs, "--json", "sysinfo"]),
+        )
+        .await
+        .unwrap_or_else(|_| Err(format!("Timed out after {}s", timeout.as_secs())))?;
+        Ok(started.elapsed())
+    }
+
+    async fn ping_command(&self) -> String {
+        let devices = std::iter::once(&self.device).chain(&self.secondary_device);
+        let results = join_all(devices.map(|device| async move {
+            match self.ping_device(device).await {
+                Ok(elapsed) => format!("**{}**: reachable, {}ms", device.name, elapsed.as_millis()),
+                Err(e) => {
+                    info!("{} didn't answer a ping: {}", device.name, e);
+                    format!("**{}**: unreachable", device.name)
+                }
+            }
+        }))
+        .await;
+        results.join("\n")
+    }
+
+    /// Re-reads the light's username and password, so rotating them doesn't
+    /// need a restart, then checks the lights still answer.
+    async fn reload_credentials_command(
+        &self,
+        ctx: &Context,
+        command: &CommandInteraction,
+    ) -> String {
+        if !self.is_owner(ctx, command.user.id).await {
+            return "This command is restricted to the bot owner.".to_string();
+        }
+
+        // Pick up edits to the env file, which the environment only has from startup
+        if std::path::Path::new(ENV_FILE).exists() {
+            if let Err(e) = reload_env_file() {
+                error!("Failed to reload config: {}", e);
+                return format!("Failed to reload credentials: {}", e);
+            }
+        }
+        let (Some(username), Some(password)) = (
+            get_optional_env_var("KASA_USERNAME"),
+            get_optional_env_var("KASA_PASSWORD"),
+        ) else {
+            return "KASA_USERNAME and KASA_PASSWORD must both be set, so the credentials weren't changed.".to_string();
+        };
+
+        // The secondary light and groups share the primary's credentials
+        info!("{} is reloading the light credentials", command.user.name);
+        *self.device.credentials.write().unwrap() = Credentials { username, password };
+
+        let devices = std::iter::once(&self.device).chain(&self.secondary_device);
+        let failures: Vec<String> = join_all(devices.map(|device| async move {
+            self.ping_device(device).await.err().map(|e| {
+                error!(
+                    "{} didn't answer with the new credentials: {}",
+                    device.name, e
+                );
+                format!("**{}**: {}", device.name, e)
+            })
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+        if failures.is_empty() {
+            "Reloaded the credentials and the lights answered.".to_string()
+        } else {
+            format!(
+                "Reloaded the credentials, but the lights didn't answer:\n{}",
+                failures.join("\n")
+            )
+        }
+    }
+
+    async fn snapshot_command(&self, command: &CommandInteraction) -> String {
+        let Some(subcommand) = command.data.options.first() else {
+            return "Unknown command".to_string();
+        };
+        if !self.has_control_role(command.member.as_deref()) {
+            return self.missing_role_message();
+        }
+        let name = match &subcommand.value {
+            CommandDataOptionValue::SubCommand(options) => options
+                .first()
+                .and_then(|option| option.value.as_str())
+                .map(str::trim)
+                .unwrap_or_default(),
+            _ => "",
+        };
+        if name.is_empty() {
+            return "Please give the snapshot a name.".to_string();
+        }
+
+        if subcommand.name == "save" {
+            let snapshot = match self.take_snapshot().await {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    error!("Error reading light for snapshot: {}", e);
+                    return "Failed to read the light's setting".to_string();
+                }
+            };
+            return match self.snapshots.save(name, snapshot).await {
+                Ok(()) => format!("Saved the light's setting as **{}**.", name),
+                Err(e) => {
+                    error!("{}", e);
+                    "Failed to save the snapshot".to_string()
+                }
+            };
+        }
+
+        let Some(snapshot) = self.snapshots.get(name).await else {
+            let names = self.snapshots.names().await;
+            return if names.is_empty() {
+                format!("There's no snapshot called **{}**.", name)
+            } else {
+                format!(
+                    "There's no snapshot called **{}**. Saved snapshots: {}",
+                    name,
+                    names.join(", ")
+                )
+            };
+        };
+        match self.restore_snapshot(snapshot).await {
+            Ok(()) => {
+                self.record_light_state(snapshot.on).await;
+                let note = match snapshot.brightness.filter(|_| snapshot.on) {
+                    Some(percent) => self.quiet_hours_note(percent),
+                    None => String::new(),
+                };
+                format!("Restored **{}**.{}", name, note)
+            }
+            Err(e) => {
+                error!("Error restoring snapshot {}: {}", name, e);
+                "Failed to restore the snapshot".to_string()
+            }
+        }
+    }
+
+    fn notify_command(&self, command: &CommandInteraction) -> String {
+        let count = command
+            .data
+            .options
+            .first()
+            .and_then(|option| option.value.as_i64())
+            .unwrap_or(1)
+            .clamp(1, MAX_NOTIFY_BLINKS as i64) as usize;
+        // Blinking takes a few seconds, so reply right away
+        let handler = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handler.blink(count).await {
+                error!("Error blinking light: {}", e);
+            }
+        });
+        format!(
+            "Blinking the light {} time{}.",
+            count,
+            if count == 1 { "" } else { "s" }
+        )
+    }
 
-use serenity::all::*;
-use serenity::async_trait;
-use serenity::builder::{CreateActionRow, CreateButton};
+    async fn at_command(&self, command: &CommandInteraction) -> String {
+        if !self.has_control_role(command.member.as_deref()) {
+            return self.missing_role_message();
+        }
 
-const CONTROL_CHANNEL_NAME: &str = "light-controls";
+        let option = |name: &str| {
+            command
+                .data
+                .options
+                .iter()
+                .find(|option| option.name == name)
+                .and_then(|option| option.value.as_str())
+                .unwrap_or_default()
+        };
+        let tz = self.guild_timezone(command.guild_id);
+        let Some(at) = next_local_time(option("time"), tz) else {
+            return format!("`{}` isn't a time like 20:00.", option("time"));
+        };
+        let action: Action = match option("action").parse() {
+            Ok(action) => action,
+            Err(e) => return format!("{}. Use on, off or brightness <percent>.", e),
+        };
 
-fn get_env_var(key: &str) -> String {
-    // First try to get from .env file
-    if let Ok(val) = dotenv::var(key) {
-        return val;
+        info!(
+            "{} scheduled a one-time {} at {}",
+            command.user.name, action, at
+        );
+        match self.schedule_one_shot(at, action).await {
+            Ok(()) => format!(
+                "Will run `{}` <t:{}:R> (<t:{}:t>).",
+                action,
+                at.timestamp(),
+                at.timestamp()
+            ),
+            Err(e) => {
+                error!("Error scheduling one-time action: {}", e);
+                format!("Failed to schedule that: {}", e)
+            }
+        }
     }
-    // Fall back to system environment variables
-    env::var(key).unwrap_or_else(|_| panic!("Expected {key} in environment"))
-}
 
-#[derive(Clone)]
-struct Handler {
-    control_channel: Arc<RwLock<Option<ChannelId>>>,
-    kasa_device_ip: String,
-    kasa_username: String,
-    kasa_password: String,
-    kasa_dir: String,
-}
+    /// Moves the next run of the on or off job by skipping it and scheduling
+    /// its action once at the shifted time. The one-time action skips the
+    /// job's extra checks, such as away detection.
+    async fn shift_command(&self, command: &CommandInteraction) -> String {
+        if !self.has_control_role(command.member.as_deref()) {
+            return self.missing_role_message();
+        }
 
-impl Handler {
-    fn new() -> Self {
-        let kasa_device_ip = get_env_var("KASA_DEVICE_IP");
-        let kasa_username = get_env_var("KASA_USERNAME");
-        let kasa_password = get_env_var("KASA_PASSWORD");
-        let kasa_dir = get_env_var("KASA_DIR");
+        let option = |name: &str| {
+            command
+                .data
+                .options
+                .iter()
+                .find(|option| option.name == name)
+                .and_then(|option| option.value.as_str())
+                .unwrap_or_default()
+        };
+        let (job, action) = match option("job") {
+            "on" => ("on", Action::On),
+            "off" => ("off", Action::Off),
+            other => return format!("Unknown job: {}", other),
+        };
+        let Some(offset) = parse_offset(option("offset"))
+            .filter(|offset| offset.num_minutes().abs() <= MAX_SHIFT_MINUTES)
+        else {
+            return format!(
+                "`{}` isn't an offset like +30m or -1h, up to {} hours either way.",
+                option("offset"),
+                MAX_SHIFT_MINUTES / 60
+            );
+        };
 
-        Self {
-            control_channel: Arc::new(RwLock::new(None)),
-            kasa_device_ip,
-            kasa_username,
-            kasa_password,
-            kasa_dir,
+        let tz = self.guild_timezone(command.guild_id);
+        let now = Utc::now().with_timezone(&tz);
+        let schedules = self.schedules();
+        let schedule = if job == "on" {
+            &schedules.on
+        } else {
+            &schedules.off
+        };
+        let Some(next) = schedule.next(&now) else {
+            return format!("The {} job has no upcoming run to move.", job);
+        };
+        let shifted = (next + offset).with_timezone(&Utc);
+        if shifted <= Utc::now() {
+            return format!(
+                "Moving the {} job at <t:{}:t> by {} would put it in the past.",
+                job,
+                next.timestamp(),
+                option("offset")
+            );
         }
-    }
 
-    async fn setup_control_channel(&self, ctx: &Context) {
-        let guilds: Vec<GuildInfo> = ctx.http.get_guilds(None, None).await.unwrap_or_default();
+        *self.skipped_jobs.write().await.entry(job).or_default() += 1;
+        if let Err(e) = self.schedule_one_shot(shifted, action).await {
+            error!("Error scheduling shifted {} job: {}", job, e);
+            self.take_skip(job).await;
+            return format!("Failed to move the {} job: {}", job, e);
+        }
+        info!(
+            "{} moved the {} job at {} to {}",
+            command.user.name, job, next, shifted
+        );
+        format!(
+            "The {} job at <t:{}:t> will run at <t:{}:t> (<t:{}:R>) instead, just this once.",
+            job,
+            next.timestamp(),
+            shifted.timestamp(),
+            shifted.timestamp()
+        )
+    }
 
-        for guild in guilds {
-            let guild_id = guild.id;
-            // Delete existing control channel if it exists
-            if let Ok(channels) = guild_id.channels(&ctx.http).await {
-                for (channel_id, channel) in channels {
-                    if channel.name == CONTROL_CHANNEL_NAME {
-                        if let Err(e) = channel_id.delete(&ctx.http).await {
-                            error!("Failed to delete old control channel: {:?}", e);
-                        }
-                    }
-                }
-            }
+    fn set_job_enabled_command(
+        &self,
+        command: &CommandInteraction,
+        subcommand: &CommandDataOption,
+    ) -> String {
+        if !self.has_control_role(command.member.as_deref()) {
+            return self.missing_role_message();
+        }
+        let enabled = subcommand.name == "enable";
+        let job = match &subcommand.value {
+            CommandDataOptionValue::SubCommand(options) => options
+                .first()
+                .and_then(|option| option.value.as_str())
+                .filter(|job| SCHEDULED_JOBS.contains(job)),
+            _ => None,
+        };
+        let Some(job) = job else {
+            return "Please pick a job.".to_string();
+        };
 
-            // Create new control channel
-            match guild_id
-                .create_channel(
-                    &ctx.http,
-                    CreateChannel::new(CONTROL_CHANNEL_NAME).kind(ChannelType::Text),
+        let state = if enabled { "enabled" } else { "disabled" };
+        info!("{} {} the {} job", command.user.name, state, job);
+        match self.set_job_enabled(job, enabled) {
+            Ok(()) => format!("The {} job is {}.", job, state),
+            Err(e) => {
+                error!("{}", e);
+                format!(
+                    "The {} job is {}, but it'll go back after a restart: {}",
+                    job, state, e
                 )
-                .await
-            {
-                Ok(channel) => {
-                    let mut control_channel = self.control_channel.write().await;
-                    *control_channel = Some(channel.id);
-
-                    // Create the control message with buttons
-                    if let Err(why) = channel
-                        .send_message(
-                            &ctx.http,
-                            CreateMessage::new()
-                                .content("Light Controls")
-                                .components(vec![
-                                    CreateActionRow::Buttons(vec![
-                                        CreateButton::new("light_on")
-                                            .label("Turn On")
-                                            .style(ButtonStyle::Success),
-                                        CreateButton::new("light_off")
-                                            .label("Turn Off")
-                                            .style(ButtonStyle::Danger),
-                                    ]),
-                                    CreateActionRow::Buttons(vec![
-                                        CreateButton::new("light_on_15")
-                                            .label("15 min")
-                                            .style(ButtonStyle::Secondary),
-                                        CreateButton::new("light_on_30")
-                                            .label("30 min")
-                                            .style(ButtonStyle::Secondary),
-                                        CreateButton::new("light_on_60")
-                                            .label("60 min")
-                                            .style(ButtonStyle::Secondary),
-                                    ]),
-                                ]),
-                        )
-                        .await
-                    {
-                        error!("Error sending control message: {:?}", why);
-                    }
-                }
-                Err(why) => error!("Error creating control channel: {:?}", why),
             }
         }
     }
 
-    async fn execute_light_command(&self, args: &[&str]) -> Result<(), String> {
-        // Log the command, but mask sensitive info if present
-        let log_args: Vec<&str> = args
-            .iter()
-            .map(|&arg| {
-                if arg.contains("username") || arg.contains("password") {
-                    "[MASKED]"
-                } else {
-                    arg
-                }
-            })
-            .collect();
-        info!("Executing kasa command with args: {:?}", log_args);
-
-        let mut command = Command::new("uv");
-        command
-            .arg("run")
-            .arg("kasa")
-            .current_dir(&self.kasa_dir)
-            .arg("--host")
-            .arg(&self.kasa_device_ip)
-            .arg("--username")
-            .arg(&self.kasa_username)
-            .arg("--password")
-            .arg(&self.kasa_password);
-
-        // Add all the additional arguments
-        for arg in args {
-            command.arg(arg);
+    async fn pause_command(&self, command: &CommandInteraction) -> String {
+        if !self.has_control_role(command.member.as_deref()) {
+            return self.missing_role_message();
         }
 
-        let output = command
-            .output()
-            .map_err(|e| format!("Failed to execute kasa command: {}", e))?;
+        let minutes = command
+            .data
+            .options
+            .first()
+            .and_then(|option| option.value.as_i64())
+            .unwrap_or_default();
+        if minutes <= 0 {
+            *self.paused_until.write().await = None;
+            info!("{} resumed automation", command.user.name);
+            return "Automation resumed.".to_string();
+        }
 
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        let stdout = String::from_utf8_lossy(&output.stdout);
+        let paused_until = Utc::now() + chrono::Duration::minutes(minutes);
+        *self.paused_until.write().await = Some(paused_until);
+        info!(
+            "{} paused automation until {}",
+            command.user.name, paused_until
+        );
+        format!(
+            "Automation paused until <t:{}:t>. Manual controls still work.",
+            paused_until.timestamp()
+        )
+    }
 
-        info!("Kasa command stdout: {}", stdout);
-        if !stderr.is_empty() {
-            error!("Kasa command stderr: {}", stderr);
+    async fn schedule_command(&self, command: &CommandInteraction) -> String {
+        if let Some(subcommand) = command
+            .data
+            .options
+            .first()
+            .filter(|subcommand| matches!(subcommand.name.as_str(), "enable" | "disable"))
+        {
+            return self.set_job_enabled_command(command, subcommand);
         }
 
-        if !output.status.success() {
-            return Err(format!("Command failed: {}", stderr));
+        let tz = self.guild_timezone(command.guild_id);
+        let now = Utc::now().with_timezone(&tz);
+        let skipped_jobs = self.skipped_jobs.read().await.clone();
+        // Discord shows the timestamp in each reader's own timezone
+        let next_run = |next: DateTime<Tz>| {
+            format!(
+                "next {}, <t:{}:f> your time (<t:{}:R>)",
+                next.format("%a %H:%M %Z"),
+                next.timestamp(),
+                next.timestamp()
+            )
+        };
+        let mut lines = vec![format!("**Schedule** ({})", tz)];
+        for (job, schedule) in self.schedules().jobs() {
+            let next = match schedule.next(&now) {
+                _ if self.job_disabled(job) => "disabled".to_string(),
+                Some(next) => next_run(next),
+                None => "never".to_string(),
+            };
+            let skips = match skipped_jobs.get(job).copied().unwrap_or_default() {
+                0 => String::new(),
+                1 => ", next run skipped".to_string(),
+                count => format!(", next {} runs skipped", count),
+            };
+            lines.push(format!("{}: {}{}", job, next, skips));
         }
-
-        Ok(())
+        for entry in &self.schedules().actions {
+            let next = match entry.schedule.next(&now) {
+                Some(next) => next_run(next),
+                None => "never".to_string(),
+            };
+            lines.push(format!("{}: {}", entry.action, next));
+        }
+        let mut one_shots: Vec<(DateTime<Utc>, Action)> = self
+            .one_shots
+            .lock()
+            .await
+            .iter()
+            .map(|one_shot| (one_shot.at, one_shot.action))
+            .collect();
+        one_shots.sort_by_key(|(at, _)| *at);
+        for (at, action) in one_shots {
+            lines.push(format!(
+                "{}: once <t:{}:R> (<t:{}:t>)",
+                action,
+                at.timestamp(),
+                at.timestamp()
+            ));
+        }
+        for window in &self.schedules().blackouts {
+            if let Some(until) = window.active_until(&now) {
+                lines.push(format!(
+                    "Blackout `{}` is active, no automation until <t:{}:t>.",
+                    window,
+                    until.timestamp()
+                ));
+            }
+        }
+        if let Some(remaining) = self.pause_remaining().await {
+            lines.push(format!(
+                "Automation is paused for another {}.",
+                format_duration(remaining)
+            ));
+        }
+        lines.join("\n")
     }
 
-    async fn set_auto_off(&self, enabled: bool, minutes: Option<u32>) -> Result<(), String> {
-        // First set the minutes if provided
-        if let Some(mins) = minutes {
-            self.execute_light_command(&["feature", "auto_off_minutes", &mins.to_string()])
-                .await?;
+    async fn auto_off_command(&self, command: &CommandInteraction) -> String {
+        let Some(subcommand) = command.data.options.first() else {
+            return "Unknown command".to_string();
+        };
+        if subcommand.name == "set" {
+            if !self.has_control_role(command.member.as_deref()) {
+                return self.missing_role_message();
+            }
+            let minutes = match &subcommand.value {
+                CommandDataOptionValue::SubCommand(options) => options
+                    .first()
+                    .and_then(|option| option.value.as_i64())
+                    .and_then(|minutes| u32::try_from(minutes).ok()),
+                _ => None,
+            };
+            let Some(minutes) = minutes else {
+                return "Please give a number of minutes.".to_string();
+            };
+            let result = self
+                .mirrored(|h| async move {
+                    if minutes == 0 {
+                        h.set_auto_off(false, None).await
+                    } else {
+                        h.set_auto_off(true, Some(minutes)).await
+                    }
+                })
+                .await;
+            if let Err(e) = result {
+                error!("Error setting auto-off: {}", e);
+                return "Failed to set auto-off".to_string();
+            }
+            // Report what the device actually stored
+            return match self.query_auto_off().await {
+                Ok(setting) => with_secondary_result(
+                    format!("Auto-off is now {}.", setting),
+                    result.ok().flatten(),
+                ),
+                Err(e) => {
+                    error!("Error reading auto-off: {}", e);
+                    "Set auto-off, but couldn't read it back".to_string()
+                }
+            };
         }
 
-        // Then enable/disable the feature
-        self.execute_light_command(&[
-            "feature",
-            "auto_off_enabled",
-            if enabled { "True" } else { "False" },
-        ])
-        .await
+        match self.query_auto_off().await {
+            Ok(setting) => format!("Auto-off is {}.", setting),
+            Err(e) => {
+                error!("Error reading auto-off: {}", e);
+                "Failed to read auto-off".to_string()
+            }
+        }
     }
 
-    async fn turn_on_timed(&self, minutes: u32) -> Result<(), String> {
-        // First turn on the light
-        self.execute_light_command(&["on"]).await?;
-        // Then set up auto-off
-        self.set_auto_off(true, Some(minutes)).await
+    async fn timers_command(&self, command: &CommandInteraction) -> String {
+        let Some(subcommand) = command.data.options.first() else {
+            return "Unknown command".to_string();
+        };
+        match subcommand.name.as_str() {
+            "list" => {
+                let timers = self.active_timers().await;
+                if timers.is_empty() {
+                    return "No timers are running.".to_string();
+                }
+                timers
+                    .iter()
+                    .map(|(device_name, ends_at)| {
+                        format!(
+                            "{}: turns off <t:{}:R> (<t:{}:t>)",
+                            device_name,
+                            ends_at.timestamp(),
+                            ends_at.timestamp()
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            "cancel" => {
+                if !self.has_control_role(command.member.as_deref()) {
+                    return self.missing_role_message();
+                }
+                let name = match &subcommand.value {
+                    CommandDataOptionValue::SubCommand(options) => options
+                        .first()
+                        .and_then(|option| option.value.as_str())
+                        .unwrap_or_default(),
+                    _ => "",
+                };
+                let Some(device) = std::iter::once(&self.device)
+                    .chain(&self.secondary_device)
+                    .find(|device| device.name.eq_ignore_ascii_case(name.trim()))
+                else {
+                    return format!("There's no light called {}.", name);
+                };
+
+                let handler = self.for_device(device.clone());
+                handler.cancel_timer().await;
+                // Otherwise the device would still turn itself off later
+                match handler.set_auto_off(false, None).await {
+                    Ok(()) => format!("Cancelled the timer for {}. It will stay on.", device.name),
+                    Err(e) => {
+                        error!("Error disabling auto-off for {}: {}", device.name, e);
+                        format!(
+                            "Cancelled the timer for {}, but couldn't turn off its auto-off.",
+                            device.name
+                        )
+                    }
+                }
+            }
+            _ => "Unknown command".to_string(),
+        }
     }
 
-    async fn turn_on_regular(&self) -> Result<(), String> {
-        // Turn on the light and disable auto-off
-        self.execute_light_command(&["on"]).await?;
-        self.set_auto_off(false, None).await
+    async fn rename_channel_command(&self, ctx: &Context, command: &CommandInteraction) -> String {
+        if !self.is_owner(ctx, command.user.id).await {
+            return "This command is restricted to the bot owner.".to_string();
+        }
+        let requested = command
+            .data
+            .options
+            .first()
+            .and_then(|option| option.value.as_str())
+            .unwrap_or_default();
+        let name = match validate_channel_name(requested) {
+            Ok(name) => name,
+            Err(e) => return e,
+        };
+
+        // Every guild's control channel shares the name, so rename them all
+        let channels: Vec<ChannelId> = self
+            .control_channels
+            .read()
+            .await
+            .values()
+            .copied()
+            .collect();
+        let mut failures = Vec::new();
+        for channel_id in channels {
+            if let Err(e) = channel_id
+                .edit(&ctx.http, EditChannel::new().name(&name))
+                .await
+            {
+                error!("Failed to rename control channel {}: {:?}", channel_id, e);
+                failures.push(format!("<#{}>", channel_id));
+            }
+        }
+
+        // Remember the name so the channels are found again after a restart
+        *self.control_channel_name.write().unwrap() = name.clone();
+        if let Err(e) = save_env_vars(&[("CONTROL_CHANNEL_NAME", &name)]) {
+            error!("{}", e);
+            return format!(
+                "Renamed the control channel to #{}, but couldn't save the name: {}",
+                name, e
+            );
+        }
+        info!(
+            "{} renamed the control channel to #{}",
+            command.user.name, name
+        );
+        if failures.is_empty() {
+            format!("Renamed the control channel to #{}.", name)
+        } else {
+            format!(
+                "Renamed the control channel to #{}, except {}.",
+                name,
+                failures.join(", ")
+            )
+        }
     }
 
-    async fn start_scheduler(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let scheduler = JobScheduler::new().await?;
-        let handler = self.clone();
+    async fn config_command(&self, ctx: &Context, command: &CommandInteraction) -> String {
+        if !self.is_owner(ctx, command.user.id).await {
+            return "This command is restricted to the bot owner.".to_string();
+        }
+        format!("```\n{}\n```", self.config_summary())
+    }
 
-        // Log current time in different timezones
-        let now = Utc::now();
-        let toronto = now.with_timezone(&Toronto);
+    /// Describes the loaded configuration. Credentials are never included.
+    fn config_summary(&self) -> String {
+        let or_none = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+        let schedules = self.schedules();
+        let mut lines = vec![
+            "[schedule]".to_string(),
+            format!("timezone = {}", self.timezone),
+        ];
+        let mut guild_timezones: Vec<_> = self.guild_timezones.iter().collect();
+        guild_timezones.sort_by_key(|(guild_id, _)| **guild_id);
+        for (guild_id, tz) in guild_timezones {
+            lines.push(format!("timezone.{} = {}", guild_id, tz));
+        }
+        lines.extend([
+            format!("off = {}", schedules.off),
+            format!("on = {}", schedules.on),
+            format!("led_on = {}", schedules.led_on),
+            format!(
+                "actions = {}",
+                or_none((!schedules.actions.is_empty()).then(|| {
+                    schedules
+                        .actions
+                        .iter()
+                        .map(|entry| format!("{} = {}", entry.schedule, entry.action))
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                }))
+            ),
+            format!(
+                "disabled = {}",
+                or_none({
+                    let mut disabled: Vec<String> =
+                        self.disabled_jobs.read().unwrap().iter().cloned().collect();
+                    disabled.sort_unstable();
+                    (!disabled.is_empty()).then(|| disabled.join(", "))
+                })
+            ),
+            format!(
+                "blackouts = {}",
+                or_none((!schedules.blackouts.is_empty()).then(|| {
+                    schedules
+                        .blackouts
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                }))
+            ),
+            format!(
+                "brightness_curve = {}",
+                or_none(self.brightness_curve.as_ref().map(ToString::to_string))
+            ),
+            format!(
+                "precedence = {:?} within {}m of a manual change",
+                self.schedule_precedence,
+                self.manual_override_window.as_secs() / 60
+            ),
+            format!(
+                "color_temp_curve = {}",
+                or_none(self.color_temp_curve.as_ref().map(ToString::to_string))
+            ),
+            format!(
+                "quiet_hours = {}",
+                or_none(
+                    self.quiet_hours
+                        .map(|(hours, max)| format!("{} at most {}%", hours, max))
+                )
+            ),
+            format!(
+                "ambient_light = {}",
+                or_none(self.darkness_check.as_ref().map(ToString::to_string))
+            ),
+            format!(
+                "announce_before = {}",
+                or_none(self.announce_before.map(format_duration))
+            ),
+            String::new(),
+            "[devices]".to_string(),
+        ]);
+        for device in std::iter::once(&self.device).chain(&self.secondary_device) {
+            lines.push(format!(
+                "{} = {} ({}, credentials [REDACTED])",
+                device.name, device.host, device.kind
+            ));
+        }
+        for group in &self.groups {
+            let members: Vec<&str> = group.devices.iter().map(|d| d.name.as_str()).collect();
+            lines.push(format!("group {} = {}", group.name, members.join(", ")));
+        }
+        lines.extend([
+            String::new(),
+            "[features]".to_string(),
+            format!(
+                "reconcile_interval = {}",
+                or_none(self.reconcile_interval.map(|d| format!("{}s", d.as_secs())))
+            ),
+            format!("reconcile_on_start = {}", self.reconcile_on_start),
+            format!(
+                "offline_queue = {}",
+                or_none(
+                    self.offline_queue_ttl
+                        .map(|ttl| format!("{}m", ttl.as_secs() / 60))
+                )
+            ),
+            format!("responses_ephemeral = {}", self.responses_ephemeral),
+            format!(
+                "sunrise = {}m in {} steps",
+                self.sunrise_duration.as_secs() / 60,
+                self.sunrise_steps
+            ),
+            format!(
+                "timer_dim = {}",
+                or_none(self.timer_dim.map(|d| format!(
+                    "{}s in {} steps",
+                    d.as_secs(),
+                    self.timer_dim_steps
+                )))
+            ),
+            format!(
+                "preserve_brightness = {}",
+                or_none(
+                    self.preserve_brightness
+                        .map(|window| format!("{}m", window.as_secs() / 60))
+                )
+            ),
+            format!("kasa_retries = {}", self.kasa_retries),
+            format!(
+                "slow_command_threshold = {}ms",
+                self.slow_command_threshold.as_millis()
+            ),
+            format!(
+                "failure_notify_threshold = {}",
+                self.failure_notify_threshold
+            ),
+            format!("units = {:?}", self.units),
+            format!(
+                "control_role = {}",
+                or_none(self.control_role_id.map(|id| id.to_string()))
+            ),
+            format!(
+                "owner = {}",
+                or_none(self.owner_id.map(|id| id.to_string()))
+            ),
+        ]);
+        lines.join("\n")
+    }
 
-        info!("Current time - UTC: {}", now);
-        info!("Current time - Toronto: {}", toronto);
+    async fn whoami_command(&self, ctx: &Context, command: &CommandInteraction) -> String {
+        let member = command.member.as_deref();
+        let roles = match member {
+            Some(member) if !member.roles.is_empty() => member
+                .roles
+                .iter()
+                .map(|role_id| format!("<@&{}>", role_id))
+                .collect::<Vec<_>>()
+                .join(", "),
+            Some(_) => "none".to_string(),
+            None => "none (not in a server)".to_string(),
+        };
+        let control = match (self.control_role_id, self.has_control_role(member)) {
+            (None, _) => "yes (no CONTROL_ROLE_ID is set)".to_string(),
+            (Some(role_id), true) => format!("yes (you have <@&{}>)", role_id),
+            (Some(role_id), false) => format!("no (you need <@&{}>)", role_id),
+        };
+        let owner = if self.is_owner(ctx, command.user.id).await {
+            "yes"
+        } else {
+            "no"
+        };
 
-        // Turn off lights at midnight
-        scheduler
-            .add(Job::new_async(
-                "0 0 0 * * * America/Toronto",
-                move |_, _| {
-                    let handler = handler.clone();
-                    Box::pin(async move {
-                        let now = Utc::now().with_timezone(&Toronto);
-                        info!("Running midnight job at {}", now);
-                        if let Err(e) = handler.execute_light_command(&["off"]).await {
-                            error!("Failed to execute midnight light off command: {}", e);
-                        } else {
-                            info!("Successfully turned off light at midnight");
-                        }
-                    })
-                },
-            )?)
-            .await?;
+        format!(
+            "User: <@{}>\nRoles: {}\nCan control the light: {}\nBot owner: {}",
+            command.user.id, roles, control, owner
+        )
+    }
 
-        // Turn on lights at 5 PM (17:00)
-        let handler = self.clone();
-        scheduler
-            .add(Job::new_async(
-                "0 0 17 * * * America/Toronto",
-                move |_, _| {
-                    let handler = handler.clone();
-                    Box::pin(async move {
-                        let now = Utc::now().with_timezone(&Toronto);
-                        info!("Running 5 PM job at {}", now);
-                        if let Err(e) = handler.execute_light_command(&["on"]).await {
-                            error!("Failed to execute 5 PM light on command: {}", e);
-                        } else {
-                            info!("Successfully turned on light at 5 PM");
-                        }
-                    })
-                },
-            )?)
-            .await?;
+    async fn run_job_command(&self, ctx: &Context, command: &CommandInteraction) -> String {
+        if !self.is_owner(ctx, command.user.id).await {
+            return "This command is restricted to the bot owner.".to_string();
+        }
 
-        // Start the scheduler
-        scheduler.start().await?;
+        let job = command
+            .data
+            .options
+            .first()
+            .and_then(|option| option.value.as_str())
+            .unwrap_or_default();
 
-        Ok(())
+        info!("Manually triggering {} job for {}", job, command.user.name);
+        let tz = self.guild_timezone(command.guild_id);
+        let schedules = self.schedules();
+        let (result, schedule) = match job {
+            "off" => (self.run_midnight_off_job(tz).await, &schedules.off),
+            "on" => (self.run_evening_on_job(tz).await, &schedules.on),
+            "led" => (self.run_morning_led_job(tz).await, &schedules.led_on),
+            _ => return format!("Unknown job: {}", job),
+        };
+        match result {
+            Ok(_) => format!("Ran the {} job ({}).", job, schedule),
+            Err(e) => format!("The {} job ({}) failed: {}", job, schedule, e),
+        }
     }
 }
 
 #[async_trait]
 impl EventHandler for Handler {
+    async fn guild_create(&self, _ctx: Context, guild: Guild, _is_new: Option<bool>) {
+        if !self.away_detection {
+            return;
+        }
+        let online: HashSet<UserId> = guild
+            .presences
+            .values()
+            .filter(|presence| presence.status != OnlineStatus::Offline)
+            .map(|presence| presence.user.id)
+            .collect();
+        self.online_users.write().await.insert(guild.id, online);
+    }
+
+    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        if self.reaction_controls {
+            self.handle_reaction(&ctx, reaction).await;
+        }
+    }
+
+    async fn presence_update(&self, _ctx: Context, presence: Presence) {
+        let Some(guild_id) = presence.guild_id.filter(|_| self.away_detection) else {
+            return;
+        };
+        let mut online_users = self.online_users.write().await;
+        let online = online_users.entry(guild_id).or_default();
+        if presence.status == OnlineStatus::Offline {
+            online.remove(&presence.user.id);
+        } else {
+            online.insert(presence.user.id);
+        }
+    }
+
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
-        if let Interaction::Component(component) = interaction {
-            // Defer the response without showing a message
-            if let Err(why) = component
-                .create_response(
-                    &ctx.http,
-                    CreateInteractionResponse::Defer(CreateInteractionResponseMessage::new()),
-                )
-                .await
-            {
-                error!("Cannot defer button response: {}", why);
-                return;
-            }
+        // Discord occasionally redelivers an interaction, which would run the
+        // action twice. The first delivery already responded, so just drop it.
+        if !self.first_delivery(interaction.id()).await {
+            warn!(
+                "Ignoring duplicate delivery of interaction {}",
+                interaction.id()
+            );
+            return;
+        }
 
-            // Process the command
-            let result = match component.data.custom_id.as_str() {
-                "light_on" => match self.turn_on_regular().await {
-                    Ok(_) => "Light turned on!".to_string(),
-                    Err(e) => {
-                        error!("Error turning light on: {}", e);
-                        "Failed to turn on light".to_string()
-                    }
-                },
-                "light_off" => match self.execute_light_command(&["off"]).await {
-                    Ok(_) => "Light turned off!".to_string(),
-                    Err(e) => {
-                        error!("Error turning light off: {}", e);
-                        "Failed to turn off light".to_string()
-                    }
-                },
-                "light_on_15" => match self.turn_on_timed(15).await {
-                    Ok(_) => {
-                        let now = Utc::now().with_timezone(&Toronto);
-                        let off_time = now + chrono::Duration::minutes(15);
-                        let timestamp = off_time.timestamp();
-                        format!(
-                            "Light turned on for 15 minutes! Will turn off <t:{}:R> (<t:{}:t>)",
-                            timestamp, timestamp
-                        )
-                    }
-                    Err(e) => {
-                        error!("Error setting timed light: {}", e);
-                        "Failed to set timed light".to_string()
-                    }
-                },
-                "light_on_30" => match self.turn_on_timed(30).await {
-                    Ok(_) => {
-                        let now = Utc::now().with_timezone(&Toronto);
-                        let off_time = now + chrono::Duration::minutes(30);
-                        let timestamp = off_time.timestamp();
-                        format!(
-                            "Light turned on for 30 minutes! Will turn off <t:{}:R> (<t:{}:t>)",
-                            timestamp, timestamp
-                        )
-                    }
-                    Err(e) => {
-                        error!("Error setting timed light: {}", e);
-                        "Failed to set timed light".to_string()
-                    }
-                },
-                "light_on_60" => match self.turn_on_timed(60).await {
-                    Ok(_) => {
-                        let now = Utc::now().with_timezone(&Toronto);
-                        let off_time = now + chrono::Duration::minutes(60);
-                        let timestamp = off_time.timestamp();
-                        format!(
-                            "Light turned on for 60 minutes! Will turn off <t:{}:R> (<t:{}:t>)",
-                            timestamp, timestamp
-                        )
+        match interaction {
+            Interaction::Component(component) => {
+                if !self
+                    .channel_allowed(component.guild_id, component.channel_id)
+                    .await
+                {
+                    let message = self.wrong_channel_message(component.guild_id).await;
+                    if let Err(e) = component
+                        .create_response(&ctx.http, rejection_response(message))
+                        .await
+                    {
+                        error!("Failed to reject component outside command channels: {}", e);
                     }
-                    Err(e) => {
-                        error!("Error setting timed light: {}", e);
-                        "Failed to set timed light".to_string()
+                    return;
+                }
+                self.handle_component(&ctx, component).await
+            }
+            Interaction::Command(command) => {
+                if !self
+                    .channel_allowed(command.guild_id, command.channel_id)
+                    .await
+                {
+                    let message = self.wrong_channel_message(command.guild_id).await;
+                    if let Err(e) = command
+                        .create_response(&ctx.http, rejection_response(message))
+                        .await
+                    {
+                        error!("Failed to reject command outside command channels: {}", e);
                     }
-                },
-                _ => "Unknown button".to_string(),
-            };
-
-            // Send the final result as a followup
-            if let Err(why) = component
-                .create_followup(
-                    &ctx.http,
-                    CreateInteractionResponseFollowup::new()
-                        .content(result)
-                        .ephemeral(true),
-                )
-                .await
-            {
-                error!("Cannot send followup message: {}", why);
+                    return;
+                }
+                self.handle_command(&ctx, command).await
             }
+            Interaction::Modal(modal) => self.handle_modal(&ctx, modal).await,
+            _ => {}
         }
     }
 
     async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
-        self.setup_control_channel(&ctx).await;
+        self.connected.store(true, Ordering::SeqCst);
+        *self.discord.write().unwrap() = Some(ctx.clone());
+        let guild_ids: Vec<GuildId> = ready.guilds.iter().map(|guild| guild.id).collect();
+        if let Err(e) = self.setup_control_channel(&ctx, &guild_ids).await {
+            error!("{}", e);
+        }
+        self.sync_light_state_on_ready(&ctx).await;
+        // Every shard, and every reconnect, gets its own ready event, but the
+        // rest only needs doing once per process
+        if self.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.register_commands(&ctx).await;
+        if let Some(addr) = self.metrics_addr.clone() {
+            tokio::spawn(metrics::serve(
+                addr,
+                self.on_time.clone(),
+                self.device.name.clone(),
+            ));
+        }
+        #[cfg(unix)]
+        self.reload_on_sighup();
         if let Err(e) = self.start_scheduler().await {
             error!("Failed to start scheduler: {}", e);
         }
+        if self.reconcile_on_start {
+            self.reconcile_schedule_on_start().await;
+        }
     }
 }
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    // Log aggregators generally want RFC 3339 timestamps in UTC
+    match get_optional_env_var("LOG_TIME_FORMAT").as_deref() {
+        None | Some("default") => tracing_subscriber::fmt::init(),
+        Some("rfc3339") => tracing_subscriber::fmt()
+            .with_timer(tracing_subscriber::fmt::time::ChronoUtc::rfc_3339())
+            .init(),
+        Some(other) => panic!("LOG_TIME_FORMAT must be default or rfc3339, got {other}"),
+    }
+
+    // Secrets can come from an HTTP secrets store instead of the environment
+    if let Some(url) = get_optional_env_var("SECRETS_URL") {
+        let token = get_optional_env_var("SECRETS_TOKEN");
+        let pointer = get_optional_env_var("SECRETS_JSON_POINTER");
+        match HttpSecretProvider::load(&url, token.as_deref(), pointer.as_deref()).await {
+            Ok(provider) => set_secret_provider(Box::new(provider)),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Err(e) = validate_config() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 
     let token = get_env_var("DISCORD_TOKEN");
 
-    let intents = GatewayIntents::GUILD_MESSAGES
+    let mut intents = GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT
         | GatewayIntents::GUILDS;
+    // Away detection needs to see who's online and which roles they have.
+    // Both are privileged intents that must be enabled in the developer portal.
+    if get_bool_env_var("AWAY_DETECTION", false) {
+        intents |= GatewayIntents::GUILD_PRESENCES | GatewayIntents::GUILD_MEMBERS;
+    }
+    if get_bool_env_var("REACTION_CONTROLS", false) {
+        intents |= GatewayIntents::GUILD_MESSAGE_REACTIONS;
+    }
 
-    let mut client = Client::builder(&token, intents)
-        .event_handler(Handler::new())
-        .await
-        .expect("Err creating client");
+    // Unset means retry forever; CI can set it to 0 to fail fast
+    let max_retries: Option<u32> = get_optional_env_var("STARTUP_MAX_RETRIES").map(|val| {
+        val.parse()
+            .unwrap_or_else(|_| panic!("STARTUP_MAX_RETRIES has an invalid value: {val}"))
+    });
+    // Unset runs a single shard. SHARD_ID runs just that one of SHARD_COUNT
+    // shards, e.g. when other processes run the rest.
+    let parse_shard_var = |key: &str| -> Option<u32> {
+        get_optional_env_var(key).map(|val| {
+            val.parse()
+                .unwrap_or_else(|_| panic!("{key} has an invalid value: {val}"))
+        })
+    };
+    let shard_count = parse_shard_var("SHARD_COUNT");
+    let shard_id = parse_shard_var("SHARD_ID");
+    match (shard_id, shard_count) {
+        (Some(id), Some(count)) if id < count => info!("Running shard {} of {}", id, count),
+        (Some(id), Some(count)) => panic!("SHARD_ID {id} must be less than SHARD_COUNT {count}"),
+        (Some(_), None) => panic!("SHARD_ID requires SHARD_COUNT"),
+        (None, Some(count)) => info!("Running all {} shards", count),
+        (None, None) => info!("Running a single shard"),
+    }
+
+    // One handler for every attempt, so a retry can't start a second
+    // scheduler, SIGHUP listener or metrics server
+    let handler = Handler::new();
+    let mut backoff = STARTUP_INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        let mut client = Client::builder(&token, intents)
+            .event_handler(handler.clone())
+            .await
+            .expect("Err creating client");
 
-    if let Err(why) = client.start().await {
+        // Stopping the shards makes the client return, ending the loop
+        let shard_manager = client.shard_manager.clone();
+        let shutdown_handler = handler.clone();
+        let shutdown = tokio::spawn(async move {
+            shutdown_signal().await;
+            info!("Shutting down");
+            shutdown_handler.shutdown_scheduler().await;
+            shard_manager.shutdown_all().await;
+        });
+
+        let result = match (shard_id, shard_count) {
+            (Some(id), Some(count)) => client.start_shard(id, count).await,
+            (None, Some(count)) => client.start_shards(count).await,
+            _ => client.start().await,
+        };
+        shutdown.abort();
+        let why = match result {
+            Ok(()) => break,
+            Err(why) => why,
+        };
         error!("Client error: {:?}", why);
+
+        // A connection that came up counts as a fresh start for the backoff
+        if handler.connected.swap(false, Ordering::SeqCst) {
+            backoff = STARTUP_INITIAL_BACKOFF;
+            attempt = 0;
+        }
+        if max_retries.is_some_and(|max_retries| attempt >= max_retries) {
+            error!("Giving up after {} retries", attempt);
+            std::process::exit(1);
+        }
+        attempt += 1;
+        warn!(
+            "Reconnecting in {}s (attempt {})",
+            backoff.as_secs(),
+            attempt
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(STARTUP_MAX_BACKOFF);
+    }
+}
+
+/// Waits for Ctrl+C, or SIGTERM from a service manager.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => error!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Failed to listen for Ctrl+C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::MockController;
+    use crate::secrets::SecretProvider;
+
+    /// Fixed settings, so tests never read the environment or `.env`.
+    struct TestConfig(HashMap<&'static str, String>);
+
+    impl SecretProvider for TestConfig {
+        fn get(&self, key: &str) -> Option<String> {
+            self.0.get(key).cloned()
+        }
+    }
+
+    /// A scratch directory for a test's files, removed when the test ends.
+    struct TestDir(std::path::PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "home-discord-bot-{}-{}",
+                name,
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn file(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn test_device(username: &str, password: &str) -> Device {
+        Device {
+            name: "Test light".to_string(),
+            host: "127.0.0.1".to_string(),
+            credentials: Arc::new(std::sync::RwLock::new(Credentials {
+                username: username.to_string(),
+                password: password.to_string(),
+            })),
+            kind: DeviceKind::Plug,
+            timeout: None,
+        }
+    }
+
+    /// A handler configured only by `settings`, keeping its files in `dir`.
+    fn test_handler(
+        device: Device,
+        controller: Arc<dyn LightController>,
+        dir: &TestDir,
+        settings: &[(&'static str, &str)],
+    ) -> Handler {
+        let mut values = HashMap::from([
+            ("HISTORY_FILE", dir.file("history.json")),
+            ("STATS_FILE", dir.file("usage_stats.json")),
+            ("SNAPSHOTS_FILE", dir.file("snapshots.json")),
+        ]);
+        values.extend(
+            settings
+                .iter()
+                .map(|&(key, value)| (key, value.to_string())),
+        );
+        Handler::with_controller(
+            device,
+            None,
+            controller,
+            ConfigSource::new(&TestConfig(values)),
+        )
+    }
+
+    #[tokio::test]
+    async fn timed_button_turns_on_with_auto_off() {
+        let dir = TestDir::new("timed-button");
+        let mock = Arc::new(MockController::default());
+        let handler = test_handler(test_device("user", "pass"), mock.clone(), &dir, &[]);
+
+        let result = handler.dispatch_component("light_on_30").await;
+
+        assert!(
+            matches!(result, Some(ComponentOutcome::Done(_))),
+            "{:?}",
+            result
+        );
+        assert_eq!(
+            mock.calls(),
+            vec![
+                vec!["on"],
+                vec!["feature", "auto_off_minutes", "30"],
+                vec!["feature", "auto_off_enabled", "True"],
+            ]
+        );
+    }
+
+    /// Collects everything the tracing subscriber writes.
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_never_contain_credentials() {
+        let capture = LogCapture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let dir = TestDir::new("credential-logs");
+        let username = "secret-user@example.com";
+        let password = "s3cret-Passw0rd";
+        let token = "s3cret-discord-token";
+        // `true` stands in for kasa, so the real subprocess logging runs
+        let controller = Arc::new(KasaCliController::with_command("true"));
+        let handler = test_handler(
+            test_device(username, password),
+            controller,
+            &dir,
+            &[("DISCORD_TOKEN", token)],
+        );
+
+        // The panel buttons and scheduled jobs, as Discord and the scheduler
+        // would run them
+        let _ = handler.dispatch_component("light_on").await;
+        let _ = handler.dispatch_component("light_on_15").await;
+        let _ = handler.dispatch_component("light_off").await;
+        let _ = handler.dispatch_component("led_on").await;
+        let _ = handler.run_evening_on_job(Toronto).await;
+        let _ = handler.run_midnight_off_job(Toronto).await;
+        // Binding to the cloud passes the credentials as a JSON argument
+        let _ = handler.set_cloud_connection(true).await;
+        // /config shows the settings to the owner
+        info!("{}", handler.config_summary());
+
+        let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.contains("Executing kasa command"),
+            "no kasa commands were logged:\n{}",
+            logs
+        );
+        assert!(!logs.contains(username), "username leaked:\n{}", logs);
+        assert!(!logs.contains(password), "password leaked:\n{}", logs);
+        assert!(!logs.contains(token), "Discord token leaked:\n{}", logs);
     }
 }
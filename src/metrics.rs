@@ -0,0 +1,114 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+/// How much of a request is read, which is plenty for the request line.
+const MAX_REQUEST_BYTES: usize = 1024;
+
+#[derive(Default)]
+struct OnTime {
+    /// When the light last came on, while it's on.
+    on_since: Option<Instant>,
+    /// On-time from before `on_since`.
+    accumulated: Duration,
+}
+
+/// How long the light has been on since the bot started, for the metrics
+/// endpoint.
+#[derive(Default)]
+pub struct OnTimeMetrics {
+    on_time: Mutex<OnTime>,
+}
+
+impl OnTimeMetrics {
+    /// Records the light's state. Repeating the current state changes nothing.
+    pub fn record(&self, on: bool) {
+        let mut on_time = self.on_time.lock().unwrap();
+        match (on, on_time.on_since) {
+            (true, None) => on_time.on_since = Some(Instant::now()),
+            (false, Some(since)) => {
+                on_time.accumulated += since.elapsed();
+                on_time.on_since = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the light is on and its total on-time so far, counting the
+    /// current stretch up to now.
+    fn snapshot(&self) -> (bool, Duration) {
+        let on_time = self.on_time.lock().unwrap();
+        let current = on_time.on_since.map(|since| since.elapsed());
+        (
+            current.is_some(),
+            on_time.accumulated + current.unwrap_or_default(),
+        )
+    }
+
+    /// The metrics in Prometheus' text format.
+    pub fn render(&self, device: &str) -> String {
+        let (on, on_time) = self.snapshot();
+        let device = device.replace('\\', "\\\\").replace('"', "\\\"");
+        format!(
+            "# HELP kasa_light_on Whether the light is on.\n\
+             # TYPE kasa_light_on gauge\n\
+             kasa_light_on{{device=\"{device}\"}} {}\n\
+             # HELP kasa_light_on_seconds_total How long the light has been on since the bot started.\n\
+             # TYPE kasa_light_on_seconds_total counter\n\
+             kasa_light_on_seconds_total{{device=\"{device}\"}} {:.3}\n",
+            u8::from(on),
+            on_time.as_secs_f64(),
+        )
+    }
+}
+
+/// Serves `/metrics` on `addr` until the process exits.
+pub async fn serve(addr: String, metrics: Arc<OnTimeMetrics>, device: String) {
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to serve metrics on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Serving metrics on {}", addr);
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        let device = device.clone();
+        tokio::spawn(async move {
+            let mut request = [0; MAX_REQUEST_BYTES];
+            let read = match stream.read(&mut request).await {
+                Ok(read) => read,
+                Err(e) => {
+                    warn!("Failed to read metrics request: {}", e);
+                    return;
+                }
+            };
+            let request = String::from_utf8_lossy(&request[..read]);
+            let response = if request.starts_with("GET /metrics ") {
+                let body = metrics.render(&device);
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            };
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                warn!("Failed to send metrics: {}", e);
+            }
+        });
+    }
+}
@@ -0,0 +1,46 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A token bucket shared by everything that sends commands to the device, so
+/// it's protected no matter where a request comes from.
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Allows bursts of up to `capacity` requests, refilling at `rate`
+    /// requests per `period`.
+    pub fn new(capacity: u32, rate: u32, period: Duration) -> Self {
+        Self {
+            capacity: capacity.into(),
+            refill_per_sec: f64::from(rate) / period.as_secs_f64(),
+            bucket: Mutex::new(Bucket {
+                tokens: capacity.into(),
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token if one is available.
+    pub fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
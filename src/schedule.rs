@@ -0,0 +1,267 @@
+use chrono::{DateTime, Duration, TimeZone, Timelike};
+use cron::Schedule;
+use std::fmt;
+use std::str::FromStr;
+
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// When a scheduled job runs, written as comma-separated `[days] HH:MM`
+/// entries such as `Mon-Fri 17:00, Sat-Sun 10:00`. Entries without days run
+/// every day. Each entry becomes a cron expression evaluated in local time.
+#[derive(Clone)]
+pub struct JobSchedule {
+    /// The entries as written, for showing to people.
+    entries: Vec<String>,
+    crons: Vec<(String, Schedule)>,
+}
+
+impl FromStr for JobSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let entries: Vec<String> = s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect();
+        let crons = entries
+            .iter()
+            .map(|entry| {
+                let cron = entry_to_cron(entry)?;
+                let schedule = Schedule::from_str(&cron)
+                    .map_err(|e| format!("Invalid schedule entry {}: {}", entry, e))?;
+                Ok((cron, schedule))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if crons.is_empty() {
+            return Err("Schedule is empty".to_string());
+        }
+        Ok(Self { entries, crons })
+    }
+}
+
+impl fmt::Display for JobSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.entries.join(", "))
+    }
+}
+
+impl JobSchedule {
+    /// The cron expressions the entries became, for logging.
+    pub fn crons(&self) -> String {
+        let crons: Vec<&str> = self.crons.iter().map(|(cron, _)| cron.as_str()).collect();
+        crons.join(" | ")
+    }
+
+    /// Whether the job is due in the minute containing `time`.
+    pub fn includes<Z: TimeZone>(&self, time: DateTime<Z>) -> bool {
+        let Some(minute) = time.with_second(0).and_then(|time| time.with_nanosecond(0)) else {
+            return false;
+        };
+        self.crons
+            .iter()
+            .any(|(_, schedule)| schedule.includes(minute.clone()))
+    }
+
+    /// The next time the job is due after `time`.
+    pub fn next<Z: TimeZone>(&self, time: &DateTime<Z>) -> Option<DateTime<Z>> {
+        self.crons
+            .iter()
+            .filter_map(|(_, schedule)| schedule.after(time).next())
+            .min()
+    }
+
+    /// The most recent time the job was due before `time`.
+    pub fn previous<Z: TimeZone>(&self, time: &DateTime<Z>) -> Option<DateTime<Z>> {
+        self.crons
+            .iter()
+            .filter_map(|(_, schedule)| schedule.after(time).next_back())
+            .max()
+    }
+}
+
+/// Whether scheduled jobs run when someone switched the light by hand
+/// shortly before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedulePrecedence {
+    /// Recent manual changes are left alone.
+    Manual,
+    /// Jobs always run.
+    Schedule,
+}
+
+impl FromStr for SchedulePrecedence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "manual" => Ok(Self::Manual),
+            "schedule" => Ok(Self::Schedule),
+            other => Err(format!("Unknown schedule precedence: {}", other)),
+        }
+    }
+}
+
+/// Something a scheduled action does to the light.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    On,
+    Off,
+    Brightness(u8),
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let action = match (words.next(), words.next()) {
+            (Some("on"), None) => Self::On,
+            (Some("off"), None) => Self::Off,
+            (Some("brightness"), Some(percent)) => percent
+                .parse()
+                .ok()
+                .filter(|percent| (1..=100).contains(percent))
+                .map(Self::Brightness)
+                .ok_or_else(|| format!("Brightness must be from 1 to 100, got {}", percent))?,
+            _ => return Err(format!("Unknown action: {}", s.trim())),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("Unexpected {} in action {}", extra, s.trim())),
+            None => Ok(action),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::On => f.write_str("on"),
+            Self::Off => f.write_str("off"),
+            Self::Brightness(percent) => write!(f, "brightness {}", percent),
+        }
+    }
+}
+
+/// An action that runs on its own schedule, such as `Sat-Sun 09:00 = brightness 40`.
+#[derive(Clone)]
+pub struct ScheduledAction {
+    pub schedule: JobSchedule,
+    pub action: Action,
+}
+
+/// Parses `SCHEDULED_ACTIONS`, a `;`-separated list of `schedule = action`
+/// entries, e.g. `Mon-Fri 07:00 = on; 22:30 = brightness 40`.
+pub fn parse_scheduled_actions(value: &str) -> Result<Vec<ScheduledAction>, String> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (schedule, action) = entry
+                .rsplit_once('=')
+                .ok_or_else(|| format!("Expected schedule = action, got {}", entry))?;
+            Ok(ScheduledAction {
+                schedule: schedule.parse()?,
+                action: action.parse()?,
+            })
+        })
+        .collect()
+}
+
+/// The longest a blackout window can last, a week.
+const MAX_BLACKOUT_MINUTES: i64 = 7 * 24 * 60;
+
+/// A recurring window in which scheduled jobs don't run, such as
+/// `Thu 19:00 = 180` for three hours from 7pm every Thursday.
+#[derive(Clone)]
+pub struct BlackoutWindow {
+    pub start: JobSchedule,
+    pub duration: Duration,
+}
+
+impl BlackoutWindow {
+    /// When the window ends, if `time` falls inside it.
+    pub fn active_until<Z: TimeZone>(&self, time: &DateTime<Z>) -> Option<DateTime<Z>> {
+        // Nudge forward so a window counts as started from its first second
+        let start = self
+            .start
+            .previous(&(time.clone() + Duration::seconds(1)))?;
+        let end = start + self.duration;
+        (end > *time).then_some(end)
+    }
+}
+
+impl fmt::Display for BlackoutWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {}", self.start, self.duration.num_minutes())
+    }
+}
+
+/// Parses `BLACKOUT_WINDOWS`, a `;`-separated list of `schedule = minutes`
+/// entries, e.g. `Thu 19:00 = 180; Sun 10:00 = 60`.
+pub fn parse_blackout_windows(value: &str) -> Result<Vec<BlackoutWindow>, String> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (start, minutes) = entry
+                .rsplit_once('=')
+                .ok_or_else(|| format!("Expected schedule = minutes, got {}", entry))?;
+            let minutes = minutes
+                .trim()
+                .parse()
+                .ok()
+                .filter(|minutes| (1..=MAX_BLACKOUT_MINUTES).contains(minutes))
+                .ok_or_else(|| {
+                    format!(
+                        "Blackout length must be from 1 to {} minutes, got {}",
+                        MAX_BLACKOUT_MINUTES,
+                        minutes.trim()
+                    )
+                })?;
+            Ok(BlackoutWindow {
+                start: start.parse()?,
+                duration: Duration::minutes(minutes),
+            })
+        })
+        .collect()
+}
+
+/// Converts `Mon-Fri 17:00` to `0 0 17 * * Mon,Tue,Wed,Thu,Fri`.
+fn entry_to_cron(entry: &str) -> Result<String, String> {
+    let (days, time) = match entry.rsplit_once(char::is_whitespace) {
+        Some((days, time)) => (Some(days.trim()), time),
+        None => (None, entry),
+    };
+    let (hour, minute) = time
+        .split_once(':')
+        .and_then(|(hour, minute)| Some((hour.parse::<u32>().ok()?, minute.parse::<u32>().ok()?)))
+        .filter(|&(hour, minute)| hour < 24 && minute < 60)
+        .ok_or_else(|| format!("Expected a time like 17:00 in {}", entry))?;
+
+    let days = match days {
+        Some(days) => {
+            let day_index = |day: &str| {
+                DAYS.iter()
+                    .position(|name| name.eq_ignore_ascii_case(day.trim()))
+                    .ok_or_else(|| format!("Unknown day {} in {}", day.trim(), entry))
+            };
+            let (first, last) = match days.split_once('-') {
+                Some((first, last)) => (day_index(first)?, day_index(last)?),
+                None => (day_index(days)?, day_index(days)?),
+            };
+            // Ranges can wrap around the end of the week, e.g. Sat-Sun
+            let count = (last + 7 - first) % 7 + 1;
+            (0..count)
+                .map(|offset| DAYS[(first + offset) % 7])
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+        None => "*".to_string(),
+    };
+
+    Ok(format!("0 {} {} * * {}", minute, hour, days))
+}
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::OnceLock;
+
+/// A source of configuration values and secrets.
+pub trait SecretProvider: Send + Sync {
+    fn get(&self, key: &str) -> Option<String>;
+}
+
+/// Reads values from a `.env` file, falling back to the process environment.
+pub struct EnvSecretProvider;
+
+impl SecretProvider for EnvSecretProvider {
+    fn get(&self, key: &str) -> Option<String> {
+        // First try to get from .env file, then fall back to system environment variables
+        dotenv::var(key).or_else(|_| env::var(key)).ok()
+    }
+}
+
+/// Reads values from a JSON object fetched once over HTTP, such as a Vault KV
+/// secret or a small secrets-manager proxy.
+pub struct HttpSecretProvider {
+    values: HashMap<String, String>,
+}
+
+impl HttpSecretProvider {
+    /// Fetches the secrets from `url`, sending `token` as a bearer token if
+    /// given. `pointer` selects a nested object in the response, e.g.
+    /// `/data/data` for Vault's KV v2 engine.
+    pub async fn load(
+        url: &str,
+        token: Option<&str>,
+        pointer: Option<&str>,
+    ) -> Result<Self, String> {
+        let mut request = reqwest::Client::new().get(url);
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+
+        let body: serde_json::Value = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| format!("Failed to fetch secrets: {}", e))?
+            .json()
+            .await
+            .map_err(|e| format!("Secrets response wasn't valid JSON: {}", e))?;
+
+        let object = match pointer {
+            Some(pointer) => body.pointer(pointer),
+            None => Some(&body),
+        }
+        .and_then(|value| value.as_object())
+        .ok_or_else(|| "Secrets response didn't contain a JSON object".to_string())?;
+
+        let values = object
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_json::Value::String(value) => value.clone(),
+                    other => other.to_string(),
+                };
+                (key.clone(), value)
+            })
+            .collect();
+        Ok(Self { values })
+    }
+}
+
+impl SecretProvider for HttpSecretProvider {
+    fn get(&self, key: &str) -> Option<String> {
+        self.values.get(key).cloned()
+    }
+}
+
+static SECRET_PROVIDER: OnceLock<Box<dyn SecretProvider>> = OnceLock::new();
+
+/// Installs the provider consulted before the environment. Must be called
+/// before any configuration is read.
+pub fn set_secret_provider(provider: Box<dyn SecretProvider>) {
+    if SECRET_PROVIDER.set(provider).is_err() {
+        panic!("Secret provider was already set");
+    }
+}
+
+/// Looks up `key` in the installed provider, falling back to the environment.
+pub fn get_secret(key: &str) -> Option<String> {
+    SECRET_PROVIDER
+        .get()
+        .and_then(|provider| provider.get(key))
+        .or_else(|| EnvSecretProvider.get(key))
+}
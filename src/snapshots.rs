@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+/// A saved light setting that `/snapshot restore` puts back.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub on: bool,
+    pub brightness: Option<u8>,
+    pub color_temp: Option<u16>,
+    pub hue_saturation: Option<(u16, u8)>,
+}
+
+/// Named snapshots, saved to a JSON file so they survive restarts.
+pub struct SnapshotStore {
+    path: PathBuf,
+    snapshots: RwLock<HashMap<String, Snapshot>>,
+}
+
+impl SnapshotStore {
+    /// Loads the snapshots saved at `path`, starting with none if there are none.
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let snapshots = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable snapshots in {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!("Failed to read snapshots from {:?}: {}", path, e);
+                HashMap::new()
+            }
+        };
+        Self {
+            path,
+            snapshots: RwLock::new(snapshots),
+        }
+    }
+
+    /// Saves `snapshot` as `name`, replacing any snapshot with that name.
+    pub async fn save(&self, name: &str, snapshot: Snapshot) -> Result<(), String> {
+        let contents = {
+            let mut snapshots = self.snapshots.write().await;
+            snapshots.insert(name.to_string(), snapshot);
+            serde_json::to_string_pretty(&*snapshots)
+                .map_err(|e| format!("Failed to serialize snapshots: {}", e))?
+        };
+        tokio::fs::write(&self.path, contents)
+            .await
+            .map_err(|e| format!("Failed to save snapshots to {:?}: {}", self.path, e))
+    }
+
+    pub async fn get(&self, name: &str) -> Option<Snapshot> {
+        self.snapshots.read().await.get(name).copied()
+    }
+
+    /// The names of every snapshot, sorted.
+    pub async fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.snapshots.read().await.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
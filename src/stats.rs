@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::sync::RwLock;
+use tracing::{error, warn};
+
+/// How often and how recently someone used the light.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct UserStats {
+    pub actions: u64,
+    /// Unix timestamp of the last action.
+    pub last_used: i64,
+}
+
+/// Per-user action counts, saved to a JSON file so they survive restarts.
+pub struct UsageStats {
+    path: PathBuf,
+    users: RwLock<HashMap<u64, UserStats>>,
+}
+
+impl UsageStats {
+    /// Loads the stats saved at `path`, starting fresh if there are none.
+    pub fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let users = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable usage stats in {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!("Failed to read usage stats from {:?}: {}", path, e);
+                HashMap::new()
+            }
+        };
+        Self {
+            path,
+            users: RwLock::new(users),
+        }
+    }
+
+    /// Counts an action by `user_id` and saves the stats.
+    pub async fn record(&self, user_id: u64, timestamp: i64) {
+        let contents = {
+            let mut users = self.users.write().await;
+            let stats = users.entry(user_id).or_insert(UserStats {
+                actions: 0,
+                last_used: timestamp,
+            });
+            stats.actions += 1;
+            stats.last_used = timestamp;
+            serde_json::to_string(&*users)
+        };
+        match contents {
+            Ok(contents) => {
+                if let Err(e) = tokio::fs::write(&self.path, contents).await {
+                    error!("Failed to save usage stats to {:?}: {}", self.path, e);
+                }
+            }
+            Err(e) => error!("Failed to serialize usage stats: {}", e),
+        }
+    }
+
+    /// Every user's stats, most active first.
+    pub async fn leaderboard(&self) -> Vec<(u64, UserStats)> {
+        let mut users: Vec<(u64, UserStats)> = self
+            .users
+            .read()
+            .await
+            .iter()
+            .map(|(&user_id, &stats)| (user_id, stats))
+            .collect();
+        users.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.actions));
+        users
+    }
+}
@@ -0,0 +1,38 @@
+use std::str::FromStr;
+
+/// How measurements are shown to users.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Units {
+    Metric,
+    Imperial,
+}
+
+impl FromStr for Units {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "metric" => Ok(Self::Metric),
+            "imperial" => Ok(Self::Imperial),
+            other => Err(format!("Unknown units: {}", other)),
+        }
+    }
+}
+
+impl Units {
+    pub fn format_temperature(self, celsius: f64) -> String {
+        match self {
+            Self::Metric => format!("{:.1} °C", celsius),
+            Self::Imperial => format!("{:.1} °F", celsius * 9.0 / 5.0 + 32.0),
+        }
+    }
+
+    /// Power is reported in watts either way, but rounded consistently.
+    pub fn format_power(self, watts: f64) -> String {
+        format!("{:.1} W", watts)
+    }
+
+    pub fn format_energy(self, kilowatt_hours: f64) -> String {
+        format!("{:.2} kWh", kilowatt_hours)
+    }
+}
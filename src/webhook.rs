@@ -0,0 +1,94 @@
+use serde_json::Value;
+use tracing::{error, warn};
+
+/// Pushes state changes to an external URL, e.g. a dashboard.
+pub struct StateWebhook {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl StateWebhook {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// POSTs `payload` as JSON. Failures are only logged, since the
+    /// dashboard is a nice-to-have.
+    pub async fn post(&self, payload: &Value) {
+        match self.client.post(&self.url).json(payload).send().await {
+            Ok(response) if !response.status().is_success() => {
+                warn!("State webhook returned {}", response.status());
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to post to state webhook: {}", e),
+        }
+    }
+}
+
+/// Used when an action webhook has a URL but no template.
+const DEFAULT_ACTION_TEMPLATE: &str = r#"{"device": "{device}", "action": "{action}", "brightness": {brightness}, "timestamp": "{timestamp}"}"#;
+
+/// A URL called when one kind of action succeeds, e.g. a different IFTTT
+/// applet for on and off. The payload is a JSON template with `{device}`,
+/// `{action}`, `{brightness}` and `{timestamp}` placeholders. Brightness is
+/// `null` for on and off.
+pub struct ActionWebhook {
+    url: String,
+    template: String,
+    client: reqwest::Client,
+}
+
+impl ActionWebhook {
+    /// Fails if the template doesn't make valid JSON once filled in.
+    pub fn new(url: String, template: Option<String>) -> Result<Self, String> {
+        let webhook = Self {
+            url,
+            template: template.unwrap_or_else(|| DEFAULT_ACTION_TEMPLATE.to_string()),
+            client: reqwest::Client::new(),
+        };
+        webhook.render(&[
+            ("device", "Light"),
+            ("action", "on"),
+            ("brightness", "50"),
+            ("timestamp", "2024-01-01T00:00:00+00:00"),
+        ])?;
+        Ok(webhook)
+    }
+
+    /// Fills in the template. Values are escaped so they can go inside JSON
+    /// strings.
+    fn render(&self, values: &[(&str, &str)]) -> Result<Value, String> {
+        let mut payload = self.template.clone();
+        for (name, value) in values {
+            let escaped = Value::String(value.to_string()).to_string();
+            payload = payload.replace(&format!("{{{}}}", name), &escaped[1..escaped.len() - 1]);
+        }
+        serde_json::from_str(&payload).map_err(|e| {
+            format!(
+                "Template isn't valid JSON once filled in ({}): {}",
+                e, payload
+            )
+        })
+    }
+
+    /// POSTs the filled-in template. Failures are only logged.
+    pub async fn fire(&self, values: &[(&str, &str)]) {
+        let payload = match self.render(values) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to build action webhook payload: {}", e);
+                return;
+            }
+        };
+        match self.client.post(&self.url).json(&payload).send().await {
+            Ok(response) if !response.status().is_success() => {
+                warn!("Action webhook returned {}", response.status());
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to post to action webhook: {}", e),
+        }
+    }
+}